@@ -0,0 +1,150 @@
+/*
+Benchmarks copypasta matching on a synthetic spammy chat log.
+
+Every message is matched against a sliding window of the messages
+before it, once with SimilarityIndex and once by comparing against
+every message in the window, for several window sizes. The share of
+messages where both find a match above the threshold shows how many
+matches the index misses.
+
+Run with `cargo bench --bench similarity`
+*/
+
+#[allow(dead_code)]
+#[path = "../src/metrics/similarity.rs"]
+mod similarity;
+
+use std::collections::{HashSet, VecDeque};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use similarity::{jaccard, shingles, SimilarityIndex};
+
+const MESSAGES: usize = 20_000;
+const WINDOWS: [usize; 4] = [10, 100, 1_000, 5_000];
+const THRESHOLD: f32 = 0.6;
+
+const COPYPASTAS: [&str; 6] = [
+    "Neuro is the best streamer and nobody can convince me otherwise, I have been here since the first stream",
+    "I'm not a bot I'm a real person who is typing this message with my own two human hands 🤖",
+    "vedal please give neuro a raise she has been working so hard every single day without a break",
+    "THIS IS MY FAVOURITE PART OF THE STREAM CHAT SPAM THE EMOTE NOW KEKW KEKW KEKW",
+    "Ｆｕｌｌ ｗｉｄｔｈ ｃｏｐｙｐａｓｔａ ｆｏｒ ｔｈｅ ｕｎｉｃｏｄｅ ｆａｎｓ ✨✨✨",
+    "evil neuro > neuro, change my mind, the tutel cannot stop us, filtered filtered filtered",
+];
+const CHATTER: [&str; 16] = [
+    "lol", "KEKW", "neuro", "LUL", "true", "what", "o7", "hi chat", "gg", "based", "🐢",
+    "tutel", "no way", "W", "filtered", "heart",
+];
+
+/// A small deterministic xorshift generator, so every run uses the same chat log
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// 40% copypastas with a few words dropped, repeated or changed, the rest short chatter
+fn spammy_chat_log() -> Vec<String> {
+    let mut rng = Rng(0x5EED_CAFE);
+    (0..MESSAGES)
+        .map(|_| {
+            if rng.below(10) < 4 {
+                let mut words: Vec<&str> = COPYPASTAS[rng.below(COPYPASTAS.len())]
+                    .split(' ')
+                    .collect();
+                for _ in 0..rng.below(4) {
+                    let position = rng.below(words.len());
+                    match rng.below(3) {
+                        0 if words.len() > 1 => {
+                            words.remove(position);
+                        }
+                        1 => words.insert(position, words[position]),
+                        _ => words[position] = CHATTER[rng.below(CHATTER.len())],
+                    }
+                }
+                words.join(" ")
+            } else {
+                (0..1 + rng.below(5))
+                    .map(|_| CHATTER[rng.below(CHATTER.len())])
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            }
+        })
+        .collect()
+}
+
+/// Match every message against the window before it with the index. Returns whether each matched
+fn run_indexed(messages: &[String], window: usize) -> (Duration, Vec<bool>) {
+    let start = Instant::now();
+    let mut index = SimilarityIndex::default();
+    let mut matched = Vec::with_capacity(messages.len());
+    for (sequence_no, text) in messages.iter().enumerate() {
+        let best = index.best_match(text);
+        matched.push(best.is_some_and(|(_, score)| score > THRESHOLD));
+        black_box(best);
+        index.insert(sequence_no, text);
+        if sequence_no >= window {
+            index.remove(sequence_no - window);
+        }
+    }
+    (start.elapsed(), matched)
+}
+
+/// Match every message against every message in the window before it
+fn run_linear(messages: &[String], window: usize) -> (Duration, Vec<bool>) {
+    let start = Instant::now();
+    let mut held: VecDeque<HashSet<u64>> = VecDeque::new();
+    let mut matched = Vec::with_capacity(messages.len());
+    for text in messages {
+        let message = shingles(text);
+        let best = held
+            .iter()
+            .map(|other| jaccard(&message, other))
+            .max_by(|a, b| a.total_cmp(b));
+        matched.push(best.is_some_and(|score| score > THRESHOLD));
+        black_box(best);
+        held.push_back(message);
+        if held.len() > window {
+            held.pop_front();
+        }
+    }
+    (start.elapsed(), matched)
+}
+
+fn main() {
+    let messages = spammy_chat_log();
+    println!("{} messages, threshold {}", messages.len(), THRESHOLD);
+    println!(
+        "{:>8} {:>14} {:>14} {:>10} {:>16}",
+        "window", "index", "linear", "speedup", "matches found"
+    );
+    for window in WINDOWS {
+        let (indexed_time, indexed_matches) = run_indexed(&messages, window);
+        let (linear_time, linear_matches) = run_linear(&messages, window);
+        let expected = linear_matches.iter().filter(|matched| **matched).count();
+        let found = indexed_matches
+            .iter()
+            .zip(linear_matches.iter())
+            .filter(|(indexed, linear)| **indexed && **linear)
+            .count();
+        println!(
+            "{:>8} {:>12.1?} {:>12.1?} {:>9.1}x {:>7}/{:<7}",
+            window,
+            indexed_time,
+            linear_time,
+            linear_time.as_secs_f64() / indexed_time.as_secs_f64(),
+            found,
+            expected
+        );
+    }
+}
@@ -1,5 +1,80 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::lookups::Lookups;
+use crate::seasons::season_dir;
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    /*
+    A Twitch channel whose chat is scored, and the directory
+    its leaderboards are read from and written to
+    */
+    pub id: String,
+    pub output_dir: PathBuf,
+    /// The season being scored. Without one, the leaderboards are kept in output_dir directly
+    pub season: Option<String>,
+    /// SQLite database every processed stream is also recorded in
+    #[cfg(feature = "sqlite")]
+    pub database: Option<PathBuf>,
+}
+
+impl Channel {
+    /// Where the leaderboards of the current season are read from and written to
+    pub fn leaderboard_dir(&self) -> PathBuf {
+        match &self.season {
+            Some(season) => season_dir(&self.output_dir, season),
+            None => self.output_dir.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricReport {
+    /*
+    A structured report a metric makes about a whole stream, saved
+    next to the leaderboards
+    */
+    pub metric_name: String,
+    pub report: serde_json::Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessedStream {
+    /*
+    Everything processing the chat of one stream produced
+    */
+    pub performances: Vec<UserChatPerformance>,
+    pub reports: Vec<MetricReport>,
+    /// When the stream started, worked out from its first comment. None if nobody chatted
+    pub started_at: Option<DateTime<Utc>>,
+    /// The badges and 7TV emotes the stream was scored with. None if it was scored without
+    pub lookups: Option<Lookups>,
+}
+
+impl ProcessedStream {
+    /// The stream under the given ID. A stream nobody chatted in is dated recorded_at, e.g.
+    /// when it was archived
+    pub fn info(&self, id: &str, recorded_at: DateTime<Utc>) -> StreamInfo {
+        StreamInfo {
+            id: id.to_string(),
+            started_at: self.started_at.unwrap_or(recorded_at),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    /*
+    The stream whose performances update the leaderboards, as
+    recorded in the leaderboards' history
+    */
+    /// The VOD ID, or a name for streams that are not VODs
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct UserChatPerformance {
@@ -46,6 +121,44 @@ impl MetadataTypes {
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeRule {
+    /*
+    How a user's metadata is combined when several comments report it
+    */
+    /// The value from the latest comment is kept
+    LastWins,
+    /// The value from the first comment is kept
+    FirstWins,
+    /// Badge lists are combined, keeping the latest version of each badge
+    Union,
+    /// Booleans are true if any comment reported true
+    Or,
+}
+
+impl MetadataTypes {
+    /// Combine the current value with a newer one. Rules that do not apply to
+    /// the type of the values, like Or on a badge list, fall back to LastWins
+    pub fn merge(&mut self, newer: &MetadataTypes, rule: MergeRule) {
+        match (rule, &mut *self, newer) {
+            (MergeRule::FirstWins, _, _) => {}
+            (MergeRule::Or, MetadataTypes::Bool(current), MetadataTypes::Bool(newer)) => {
+                *current |= *newer;
+            }
+            (MergeRule::Union, MetadataTypes::BadgeList(current), MetadataTypes::BadgeList(newer)) => {
+                for badge in newer {
+                    match current.iter_mut().find(|b| b.description == badge.description) {
+                        Some(existing) => *existing = badge.clone(),
+                        None => current.push(badge.clone()),
+                    }
+                }
+            }
+            _ => *self = newer.clone(),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct MetricUpdate {
     pub metric_name: String,
@@ -3,6 +3,7 @@ Publically accessible leaderboard types
 */
 
 use super::clptypes::BadgeInformation;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -16,6 +17,16 @@ pub struct LeaderboardExportItem {
     pub badges: Option<Vec<BadgeInformation>>,
 }
 
+#[derive(Debug, Clone)]
+pub struct LeaderboardSnapshot {
+    /*
+    A leaderboard as it was saved after a stream
+    */
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))] // Recorded in the database
+    pub name: String,
+    pub items: Vec<LeaderboardExportItem>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct LeaderboardInnerState {
     pub id: String,
@@ -25,4 +36,23 @@ pub struct LeaderboardInnerState {
     pub previous_rank: Option<u32>,
     pub elo: f32,
     pub score: f32,
+    /// Whether the user has a score in the stream being added
+    pub took_part: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LeaderboardHistoryItem {
+    /*
+    How a stream changed the standing of a user who took part in it,
+    appended to the leaderboard's history
+    */
+    pub stream_id: String,
+    pub started_at: DateTime<Utc>,
+    pub id: String,
+    pub username: String,
+    pub score: f32,
+    pub elo_before: f32,
+    pub elo_after: f32,
+    pub rank: u32,
+    pub delta: i64,
 }
@@ -2,6 +2,7 @@
 Contains all the Twitch types parsable from the chat log
 */
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -57,6 +58,10 @@ pub struct Comment {
     Represents a comment in a chat.
     */
     pub _id: String,
+    /// When the comment was sent
+    pub created_at: DateTime<Utc>,
+    /// Seconds from the start of the stream to the comment
+    pub content_offset_seconds: f64,
     pub message: ChatMessage,
     pub commenter: ChatUserInfo,
 }
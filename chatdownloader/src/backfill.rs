@@ -2,40 +2,256 @@
 A function to backfill given video IDs
 */
 
+use chrono::Utc;
 use log::info;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
+use crate::_types::clptypes::Channel;
 use crate::chatlogprocessor::ChatLogProcessor;
+use crate::chatsources::archive::ChatArchive;
+use crate::chatsources::chatsourcetrait::ChatSource;
+use crate::config::Config;
+use crate::leaderboards::{all_time_dir, Backup, LeaderboardProcessor};
 use crate::twitch_utils::TwitchAPIWrapper;
-use crate::twitchdownloaderproxy::TwitchChatDownloader;
-
-const VIDEO_IDS: [&str; 12] = [
-    "2170316549",
-    "2171991671",
-    "2172878349",
-    "2176205867",
-    "2175349344",
-    "2178862405",
-    "2188296968",
-    "2187465183",
-    "2182332760",
-    "2181468979",
-    "2180615386",
-    "2179780834",
-];
-
-pub async fn backfill() {
-    let twitch = TwitchAPIWrapper::new().await.unwrap();
-    let mut downloader = TwitchChatDownloader::new();
-
-    for video_id in VIDEO_IDS.iter() {
-        info!("Backfilling for video ID: {}", video_id);
-        // let chat_log = downloader.download_chat(video_id).await.unwrap();
-        let chat_log = downloader.download_chat(video_id).await.expect("Could not download chat log: {e:?}");
-
-        let user_performances = ChatLogProcessor::new(&twitch)
-            .parse_from_log_object(chat_log)
-            .await;
-
-        ChatLogProcessor::export_to_leaderboards(user_performances).await;
+
+/// Read the video IDs from a file, one per line. Blank lines and lines starting with `#` are skipped
+pub fn read_video_ids(path: &Path) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Process the VODs in order. The badges and 7TV emotes looked up for each VOD are saved in the
+/// archive. Without twitch, the ones saved in the archive are used, or they are not resolved
+pub async fn backfill<S: ChatSource>(
+    twitch: Option<&TwitchAPIWrapper>,
+    source: &mut S,
+    archive: Option<&ChatArchive>,
+    channel: &Channel,
+    config: &Config,
+    video_ids: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    replay(twitch, source, archive, channel, config, video_ids, Backup::Generation).await
+}
+
+/// Process the VODs in order, backing up the leaderboards each one replaces as given
+async fn replay<S: ChatSource>(
+    twitch: Option<&TwitchAPIWrapper>,
+    source: &mut S,
+    archive: Option<&ChatArchive>,
+    channel: &Channel,
+    config: &Config,
+    video_ids: &[String],
+    backup: Backup,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for video_id in video_ids.iter() {
+        info!(
+            "Backfilling for video ID: {} of channel {} using the {} source",
+            video_id,
+            channel.id,
+            source.get_name()
+        );
+        let comment_stream = source.get_comment_stream(video_id).await?;
+
+        let processor = match (twitch, archive) {
+            (Some(twitch), _) => ChatLogProcessor::new(twitch, channel, config),
+            (None, Some(archive)) => ChatLogProcessor::offline(channel, config)
+                .with_lookups(archive.read_lookups(video_id)?),
+            (None, None) => ChatLogProcessor::offline(channel, config),
+        };
+        let processed = processor.parse_from_stream(comment_stream).await?;
+        let archived_at = match archive {
+            Some(archive) => archive.started_at(video_id)?,
+            None => None,
+        };
+        let stream = processed.info(video_id, archived_at.unwrap_or_else(Utc::now));
+        if let (Some(_), Some(archive), Some(lookups)) = (twitch, archive, &processed.lookups) {
+            archive.store_lookups(video_id, lookups)?;
+        }
+
+        ChatLogProcessor::export(
+            processed.performances,
+            processed.reports,
+            &stream,
+            channel,
+            config,
+            backup,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Discard the channel's leaderboards of the current season and rebuild them from the VODs.
+/// The leaderboards as they were are backed up as one generation, which the replayed VODs add
+/// to, so a single rollback undoes the whole recompute.
+/// With verify, they are rebuilt twice and it is an error if the two results differ
+pub async fn recompute<S: ChatSource>(
+    twitch: Option<&TwitchAPIWrapper>,
+    source: &mut S,
+    archive: Option<&ChatArchive>,
+    channel: &Channel,
+    config: &Config,
+    video_ids: &[String],
+    verify: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Recomputing leaderboards from {} VODs", video_ids.len());
+    LeaderboardProcessor::clear(channel, config, Backup::Generation)?;
+    replay(twitch, source, archive, channel, config, video_ids, Backup::ExtendLatest).await?;
+    if !verify {
+        return Ok(());
+    }
+
+    let first = digest_outputs(channel, video_ids)?;
+    info!("Recomputing again to verify the result");
+    LeaderboardProcessor::clear(channel, config, Backup::ExtendLatest)?;
+    replay(twitch, source, archive, channel, config, video_ids, Backup::ExtendLatest).await?;
+    let second = digest_outputs(channel, video_ids)?;
+
+    let differences: Vec<String> = first
+        .keys()
+        .chain(second.keys().filter(|path| !first.contains_key(*path)))
+        .filter(|path| first.get(*path) != second.get(*path))
+        .map(|path| path.display().to_string())
+        .collect();
+    if !differences.is_empty() {
+        return Err(format!(
+            "Recomputing twice gave different results in: {}",
+            differences.join(", ")
+        )
+        .into());
+    }
+    info!("Both recomputes produced identical results in {} files", first.len());
+    Ok(())
+}
+
+/// SHA-256 of every file a recompute writes: the leaderboards of the current season with their
+/// configs and histories, the all-time leaderboards, and the reports on the VODs
+fn digest_outputs(channel: &Channel, video_ids: &[String]) -> io::Result<BTreeMap<PathBuf, String>> {
+    let mut paths = Vec::new();
+    let mut dirs = vec![channel.leaderboard_dir(), all_time_dir(channel)];
+    while let Some(dir) = dirs.pop() {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                // A season-less leaderboard directory also holds other seasons, the backups,
+                // the reports on every VOD and the other channels, so only the histories are read
+                if path.file_name().is_some_and(|name| name == "history") {
+                    dirs.push(path);
+                }
+            } else {
+                paths.push(path);
+            }
+        }
+    }
+    let reports_dir = channel.leaderboard_dir().join("reports");
+    if reports_dir.is_dir() {
+        for entry in fs::read_dir(&reports_dir)? {
+            let path = entry?.path();
+            let is_recomputed = path.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                video_ids.iter().any(|video_id| name.starts_with(&format!("{}.", video_id)))
+            });
+            if is_recomputed {
+                paths.push(path);
+            }
+        }
+    }
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let digest = hex::encode(Sha256::digest(fs::read(&path)?));
+            Ok((path, digest))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::_types::twitchtypes::ChatLog;
+    use crate::chatsources::archive::ArchivedChatSource;
+    use crate::chatsources::localfile::LocalChatSource;
+    use crate::leaderboards::rollback;
+    use tempfile::TempDir;
+
+    /// Twelve short streams, one of them without any chat
+    const FIXTURE_ARCHIVE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/archive");
+
+    /// An archive holding the fixture streams, and a channel scoring season 2.1 next to it
+    fn setup() -> (TempDir, ChatArchive, Channel) {
+        let dir = TempDir::new().unwrap();
+        let archive = ChatArchive::new(dir.path().join("archive"));
+        for entry in fs::read_dir(FIXTURE_ARCHIVE).unwrap() {
+            let path = entry.unwrap().path();
+            let chat_log: ChatLog = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            let vod_id = path.file_stem().unwrap().to_string_lossy();
+            archive.store(&vod_id, &chat_log).unwrap();
+        }
+        let channel = Channel {
+            id: "85498365".to_string(),
+            output_dir: dir.path().join("output"),
+            season: Some("2.1".to_string()),
+            #[cfg(feature = "sqlite")]
+            database: None,
+        };
+        fs::create_dir_all(channel.leaderboard_dir()).unwrap();
+        (dir, archive, channel)
+    }
+
+    async fn recompute_offline(archive: &ChatArchive, channel: &Channel, video_ids: &[String]) {
+        let mut local = LocalChatSource::new(PathBuf::from("missing"));
+        let mut source = ArchivedChatSource::new(&mut local, archive.clone());
+        recompute(None, &mut source, Some(archive), channel, &Config::default(), video_ids, false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn recomputing_twice_gives_identical_files() {
+        let (_dir, archive, channel) = setup();
+        let video_ids = archive.vod_ids_in_broadcast_order().unwrap();
+        assert_eq!(video_ids.first().map(String::as_str), Some("3001"));
+        assert_eq!(video_ids.last().map(String::as_str), Some("3007"));
+
+        recompute_offline(&archive, &channel, &video_ids).await;
+        let first = digest_outputs(&channel, &video_ids).unwrap();
+        recompute_offline(&archive, &channel, &video_ids).await;
+        let second = digest_outputs(&channel, &video_ids).unwrap();
+
+        assert!(first.keys().any(|path| path.ends_with("2.1/overall.json")));
+        assert!(first.keys().any(|path| path.ends_with("all-time/overall.json")));
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn one_rollback_undoes_a_recompute() {
+        let (_dir, archive, channel) = setup();
+        let video_ids = archive.vod_ids_in_broadcast_order().unwrap();
+        let mut local = LocalChatSource::new(PathBuf::from("missing"));
+        let mut source = ArchivedChatSource::new(&mut local, archive.clone());
+        backfill(None, &mut source, Some(&archive), &channel, &Config::default(), &video_ids[..2])
+            .await
+            .unwrap();
+        let before = digest_outputs(&channel, &[]).unwrap();
+
+        // More streams are replayed than backups are kept
+        recompute_offline(&archive, &channel, &video_ids).await;
+        assert_ne!(digest_outputs(&channel, &[]).unwrap(), before);
+
+        assert!(rollback(&channel.output_dir).unwrap().is_some());
+        assert_eq!(digest_outputs(&channel, &[]).unwrap(), before);
     }
 }
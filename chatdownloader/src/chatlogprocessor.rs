@@ -1,17 +1,23 @@
 use futures::join;
 use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
 use std::time::Instant;
-use std::{collections::HashMap, fs};
-use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 
-use crate::_types::clptypes::{MetadataTypes, MetadataUpdate, MetricUpdate, UserChatPerformance};
-use crate::_types::twitchtypes::{ChatLog, Comment};
+use crate::_types::clptypes::{Channel, MergeRule, MetadataTypes, MetadataUpdate, MetricReport, MetricUpdate, ProcessedStream, StreamInfo, UserChatPerformance};
+use crate::chatsources::commentstream::CommentStream;
+use crate::config::Config;
+use crate::lookups::Lookups;
+#[cfg(feature = "sqlite")]
+use crate::database::Database;
 use crate::twitch_utils::TwitchAPIWrapper;
 
-use crate::leaderboards::LeaderboardProcessor;
+use crate::leaderboards::{Backup, LeaderboardProcessor, Transaction};
 use crate::metadata::setup_metadata_and_channels;
 use crate::metrics::setup_metrics_and_channels;
+use crate::pipeline::{batch_comments, FanOut};
 
 pub struct ChatLogProcessor<'a> {
     /*
@@ -21,115 +27,228 @@ pub struct ChatLogProcessor<'a> {
     chat messages, the metadata package to extract any user-metadata,
     and the leaderboards package to export the metrics / required user
     metadata to the right people
+
+    When twitch is None the processor runs offline: no Twitch or 7TV
+    requests are made, so badges and 7TV emotes are only resolved with
+    lookups saved by an earlier run
+
+    Badges and 7TV emotes are looked up for the given channel, and
+    comments are scored with the weights in config
     */
-    twitch: &'a TwitchAPIWrapper,
+    twitch: Option<&'a TwitchAPIWrapper>,
+    channel: &'a Channel,
+    config: &'a Config,
+    lookups: Option<Lookups>,
 }
 
 impl<'a> ChatLogProcessor<'a> {
-    pub fn new(twitch: &'a TwitchAPIWrapper) -> Self {
-        Self { twitch }
+    pub fn new(twitch: &'a TwitchAPIWrapper, channel: &'a Channel, config: &'a Config) -> Self {
+        Self {
+            twitch: Some(twitch),
+            channel,
+            config,
+            lookups: None,
+        }
     }
 
-    pub fn __parse_to_log_struct(&self, chat_log_path: String) -> ChatLog {
-        let chat_log_str = fs::read_to_string(chat_log_path).unwrap();
-        let chat_log: ChatLog = serde_json::from_str(&chat_log_str).unwrap();
-        chat_log
+    /// A processor that never touches the network
+    pub fn offline(channel: &'a Channel, config: &'a Config) -> Self {
+        Self {
+            twitch: None,
+            channel,
+            config,
+            lookups: None,
+        }
     }
 
-    pub async fn parse_from_log_object(&self, chat_log: ChatLog) -> Vec<UserChatPerformance> {
+    /// Use the given badges and 7TV emotes instead of looking them up
+    pub fn with_lookups(mut self, lookups: Option<Lookups>) -> Self {
+        self.lookups = lookups;
+        self
+    }
+
+    /// Process the comments of a stream as they arrive, returning the user performances, the
+    /// reports the metrics made and the lookups they were made with. Fails if the stream could
+    /// not be read to the end
+    pub async fn parse_from_stream(
+        &self,
+        comment_stream: CommentStream,
+    ) -> Result<ProcessedStream, Box<dyn std::error::Error>> {
         let start_time = Instant::now();
         debug!("Starting chat log processing");
 
-        debug!("Instantiated metric and metadata processors");
+        let lookups = match (&self.lookups, self.twitch) {
+            (Some(lookups), _) => Some(lookups.clone()),
+            (None, Some(twitch)) => Some(Lookups::fetch(twitch, &self.channel.id).await),
+            (None, None) => {
+                info!("Running offline without saved lookups, badges and 7TV emotes are not resolved");
+                None
+            }
+        };
 
         debug!("Setting up channels for metric and metadata processors");
-        let (mut metric_processor, metric_sender, metric_receiver) =
-            setup_metrics_and_channels().await;
-
-        let (mut metadata_processor, metadata_sender, metadata_receiver) =
-            setup_metadata_and_channels(self.twitch).await;
+        let mut fan_out = FanOut::default();
+        let (metric_processor, metric_receiver) = setup_metrics_and_channels(
+            lookups.as_ref(),
+            &self.channel.id,
+            self.config,
+            fan_out.subscribe("The metric processor"),
+        )
+        .await;
+        let (metadata_processor, metadata_receiver) = setup_metadata_and_channels(
+            self.twitch,
+            lookups.as_ref(),
+            &self.channel.id,
+            self.config,
+            fan_out.subscribe("The metadata processor"),
+        )
+        .await;
+        debug!("Instantiated metric and metadata processors");
 
-        info!("Parsing chat log object");
-        let chat_adder =
-            chatlog_to_receiver(chat_log, vec![metric_sender, metadata_sender]);
-        let performances = user_chat_performance_processor(metric_processor.defaults.clone(), metric_receiver, metadata_processor.defaults.clone(), metadata_receiver);
+        info!("Parsing chat log stream");
+        let (comment_receiver, producer) = comment_stream.into_parts();
+        let chat_adder = batch_comments(comment_receiver, fan_out);
+        let performances = user_chat_performance_processor(metric_processor.defaults.clone(), metric_receiver, metadata_processor.defaults.clone(), metadata_processor.merge_rules.clone(), metadata_receiver);
 
-        let (_, _, _, performances) = join!(
+        let (comment_count, sent, metric_result, metadata_result, performances) = join!(
+            producer,
             chat_adder,
-            async move {metric_processor.run().await;},
-            async move {metadata_processor.run().await;},
+            metric_processor.run(),
+            metadata_processor.run(),
             performances,
         );
+        // A processor that stopped early also makes the stages before it fail, so its error comes first
+        let reports = metric_result?;
+        metadata_result?;
+        let (_, started_at) = sent?;
+        let comment_count = comment_count?;
         info!(
-            "Chat log processing took: {:#?}",
+            "Chat log processing of {} comments took: {:#?}",
+            comment_count,
             start_time.elapsed()
         );
-        performances.into_values().collect()
+        let mut performances: Vec<UserChatPerformance> = performances.into_values().collect();
+        if let Some(twitch) = self.twitch {
+            fill_missing_avatars(twitch, &mut performances).await;
+        }
+        Ok(ProcessedStream {
+            performances,
+            reports,
+            started_at,
+            lookups,
+        })
     }
 
-    #[allow(dead_code)]
-    async fn parse(&self, chat_log_path: String) -> Vec<UserChatPerformance> {
-        let chat_log = self.__parse_to_log_struct(chat_log_path);
-        self.parse_from_log_object(chat_log).await
+    pub async fn parse(&self, chat_log_path: &Path) -> Result<ProcessedStream, Box<dyn std::error::Error>> {
+        self.parse_from_stream(CommentStream::from_file(chat_log_path.to_path_buf()))
+            .await
     }
 
-    /// A function to export the user performances to the leaderboards and save them
-    pub async fn export_to_leaderboards(performances: Vec<UserChatPerformance>) {
-        let mut leaderboard_processor = LeaderboardProcessor::new();
-        leaderboard_processor.run(performances).await;
+    /// Save the metrics' reports on a stream and add its user performances to the channel's
+    /// leaderboards, committed together in one transaction backed up as given. The stream is
+    /// then recorded in the channel's database if it has one
+    pub async fn export(
+        performances: Vec<UserChatPerformance>,
+        reports: Vec<MetricReport>,
+        stream: &StreamInfo,
+        channel: &Channel,
+        config: &Config,
+        backup: Backup,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        #[cfg(feature = "sqlite")]
+        let recorded_performances = channel.database.as_ref().map(|_| performances.clone());
+
+        let transaction = Transaction::begin(&channel.output_dir)?;
+        Self::export_reports(reports, &stream.id, channel, &transaction)?;
+        let mut leaderboard_processor = LeaderboardProcessor::new(channel, config);
+        #[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+        let snapshots = leaderboard_processor.run(performances, stream, &transaction).await?;
+        transaction.commit_with(backup)?;
+        info!("Committed the reports and leaderboards of channel {}", channel.id);
+
+        // The database is only written once the files are committed, so it never holds a
+        // stream they do not. Processing the stream again replaces its rows
+        #[cfg(feature = "sqlite")]
+        if let (Some(path), Some(performances)) = (&channel.database, recorded_performances) {
+            Database::open(path)?
+                .record_stream(channel, stream, &performances, &snapshots)
+                .map_err(|e| {
+                    format!(
+                        "Committed the leaderboards, but could not record stream {} in {}: {}",
+                        stream.id,
+                        path.display(),
+                        e
+                    )
+                })?;
+        }
+        Ok(())
     }
-}
 
-/// A function to apwn a thread to take a ChatLog and add its comments to a receiver
-pub async fn chatlog_to_receiver(
-    chat_log: ChatLog,
-    senders: Vec<broadcast::Sender<(Comment, u32)>>,
-) {
-    for (sequence_no, comment) in chat_log.comments.iter().enumerate() {
-        for sender in senders.iter() {
-            sender.send((comment.clone(), sequence_no as u32)).unwrap();
-            tokio::task::yield_now().await;
+    /// Stage the metrics' reports on a stream in the reports directory next to the channel's
+    /// leaderboards, as <stream_id>.<metric name>.json
+    fn export_reports(
+        reports: Vec<MetricReport>,
+        stream_id: &str,
+        channel: &Channel,
+        transaction: &Transaction,
+    ) -> io::Result<()> {
+        let reports_dir = channel.leaderboard_dir().join("reports");
+        for report in reports {
+            let path = reports_dir.join(format!("{}.{}.json", stream_id, report.metric_name));
+            let data = serde_json::to_string_pretty(&report.report)?;
+            transaction.write(&path, data.as_bytes())?;
+            debug!("Staged {} report as {}", report.metric_name, path.display());
         }
+        Ok(())
     }
-    debug!("Finished adding comments to receivers");
 }
 
 /// A function to spawn a thread that takes two recievers and processes metrics / metadata from them and updates the user performances
+/// Repeated metadata updates for a user are combined with the metadata's merge rule
 pub async fn user_chat_performance_processor(
     metric_defaults: HashMap<String, f32>,
-    mut metric_receiver: mpsc::Receiver<MetricUpdate>,
+    mut metric_receiver: mpsc::Receiver<Vec<MetricUpdate>>,
     metadata_defaults: HashMap<String, MetadataTypes>,
-    mut metadata_receiver: mpsc::Receiver<MetadataUpdate>,
+    metadata_merge_rules: HashMap<String, MergeRule>,
+    mut metadata_receiver: mpsc::Receiver<Vec<MetadataUpdate>>,
 ) -> HashMap<String, UserChatPerformance> {
     let mut user_performances: HashMap<String, UserChatPerformance> = HashMap::new();
+    // (user ID, metadata name) of every metadata value that was updated at least once
+    let mut updated_metadata: HashSet<(String, String)> = HashSet::new();
     loop {
         tokio::select! {
-            Some(metric_update) = metric_receiver.recv() => {
-                metric_update.updates.iter().for_each(|(user_id, met_value)| {
-                    let user_chat_performance = get_performance_or_default(&mut user_performances, user_id, &metric_defaults, &metadata_defaults);
-                    user_chat_performance.metrics.entry(metric_update.metric_name.clone()).and_modify(|metric_value| *metric_value += met_value);
-                });
+            Some(metric_updates) = metric_receiver.recv() => {
+                for metric_update in metric_updates {
+                    metric_update.updates.iter().for_each(|(user_id, met_value)| {
+                        let user_chat_performance = get_performance_or_default(&mut user_performances, user_id, &metric_defaults, &metadata_defaults);
+                        user_chat_performance.metrics.entry(metric_update.metric_name.clone()).and_modify(|metric_value| *metric_value += met_value);
+                    });
+                }
             }
-            Some(metadata_update) = metadata_receiver.recv() => {
-                metadata_update.updates.iter().for_each(|(user_id, met_value)| {
-                    let user_chat_performance = get_performance_or_default(&mut user_performances, user_id, &metric_defaults, &metadata_defaults);
-                    match metadata_update.metadata_name.as_str() {
-                        "basic_info" => {
-                            if let Some((username, avatar)) = met_value.get_basic_info() {
-                                user_chat_performance.username = username;
-                                user_chat_performance.avatar = avatar;
-                            } else {
-                                warn!("Could not get username and/or url for user_id {}. Skipping", user_id);
-                            }
+            Some(metadata_updates) = metadata_receiver.recv() => {
+                for metadata_update in metadata_updates {
+                    let name = &metadata_update.metadata_name;
+                    let merge_rule = metadata_merge_rules.get(name).copied().unwrap_or(MergeRule::LastWins);
+                    metadata_update.updates.iter().for_each(|(user_id, met_value)| {
+                        let first_update = updated_metadata.insert((user_id.clone(), name.clone()));
+                        let user_chat_performance = get_performance_or_default(&mut user_performances, user_id, &metric_defaults, &metadata_defaults);
+                        let Some(metadata_value) = user_chat_performance.metadata.get_mut(name) else {
+                            warn!("Got an update for unknown metadata {}. Skipping", name);
+                            return;
+                        };
+                        if first_update {
+                            *metadata_value = met_value.clone();
+                        } else {
+                            metadata_value.merge(met_value, merge_rule);
                         }
-                        _ => {
-                            if let Some(metadata_value) = user_chat_performance.metadata.get_mut(&metadata_update.metadata_name) {
-                                *metadata_value = met_value.clone();
-                                debug!("Updating metadata: {} with value: {:?}", metadata_update.metadata_name, met_value);
-                            }
+                        debug!("Updating metadata: {} with value: {:?}", name, metadata_value);
+
+                        if let Some((username, avatar)) = metadata_value.get_basic_info() {
+                            user_chat_performance.username = username;
+                            user_chat_performance.avatar = avatar;
                         }
-                    }
-                });
+                    });
+                }
             }
             else => break,
         }
@@ -138,6 +257,32 @@ pub async fn user_chat_performance_processor(
     user_performances
 }
 
+/// Look up the avatars of users whose comments came without one, as they do from the GraphQL
+/// and IRC sources. Users stay without one if the lookup fails
+async fn fill_missing_avatars(twitch: &TwitchAPIWrapper, performances: &mut [UserChatPerformance]) {
+    let user_ids: Vec<String> = performances
+        .iter()
+        .filter(|performance| performance.avatar.is_empty())
+        .map(|performance| performance.id.clone())
+        .collect();
+    if user_ids.is_empty() {
+        return;
+    }
+    info!("Looking up the avatars of {} users", user_ids.len());
+    match twitch.get_profile_images(&user_ids).await {
+        Ok(profile_images) => {
+            for performance in performances.iter_mut() {
+                if let Some(profile_image) = profile_images.get(&performance.id) {
+                    if performance.avatar.is_empty() {
+                        performance.avatar = profile_image.clone();
+                    }
+                }
+            }
+        }
+        Err(e) => warn!("Unable to look up the avatars of users: {}", e),
+    }
+}
+
 /// Get a user performance or create a new one if it doesn't exist
 fn get_performance_or_default<'a>(
     user_performances: &'a mut HashMap<String, UserChatPerformance>,
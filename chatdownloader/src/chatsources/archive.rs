@@ -0,0 +1,250 @@
+/*
+An archive of the chat logs of every processed VOD, so the leaderboards
+can be recomputed from the raw chat without downloading it again.
+
+Each chat log is kept gzip compressed as <vod_id>.json.gz, in the same
+format as a TwitchDownloader chat JSON. index.json records when each
+archived stream started, so they can be replayed in broadcast order, and
+when it was archived, which dates streams nobody chatted in.
+The badges and 7TV emotes a VOD was scored with online are kept as
+<vod_id>.lookups.json, so replaying it offline scores it the same way
+*/
+
+use chrono::{DateTime, TimeDelta, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+
+use crate::_types::twitchtypes::{ChatLog, Comment};
+use crate::chatsources::chatsourcetrait::ChatSource;
+use crate::chatsources::commentstream::CommentStream;
+use crate::lookups::Lookups;
+
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ArchivedStream {
+    /// When the stream started, worked out from its first comment. None if nobody chatted
+    pub started_at: Option<DateTime<Utc>>,
+    pub comments: usize,
+    /// When the chat log was archived. None for streams archived before it was recorded
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatArchive {
+    /*
+    The directory the chat logs are archived in
+    */
+    dir: PathBuf,
+}
+
+impl ChatArchive {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path_for(&self, vod_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json.gz", vod_id))
+    }
+
+    pub fn contains(&self, vod_id: &str) -> bool {
+        self.path_for(vod_id).is_file()
+    }
+
+    fn read_index(&self) -> io::Result<BTreeMap<String, ArchivedStream>> {
+        let path = self.dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// The archived VOD IDs in the order they were broadcast. Streams without a known start
+    /// come last, ordered by ID
+    pub fn vod_ids_in_broadcast_order(&self) -> io::Result<Vec<String>> {
+        let mut streams: Vec<(String, ArchivedStream)> = self.read_index()?.into_iter().collect();
+        streams.sort_by(|(a_id, a), (b_id, b)| {
+            let a_key = (a.started_at.is_none(), a.started_at, a_id.len(), a_id);
+            let b_key = (b.started_at.is_none(), b.started_at, b_id.len(), b_id);
+            a_key.cmp(&b_key)
+        });
+        Ok(streams.into_iter().map(|(vod_id, _)| vod_id).collect())
+    }
+
+    /// When an archived VOD started, or for a VOD nobody chatted in, when it was archived.
+    /// Unlike the current time, it is the same every time the VOD is replayed
+    pub fn started_at(&self, vod_id: &str) -> io::Result<Option<DateTime<Utc>>> {
+        Ok(self
+            .read_index()?
+            .remove(vod_id)
+            .and_then(|stream| stream.started_at.or(stream.archived_at)))
+    }
+
+    /// Start archiving the chat log of a VOD. Nothing is in the archive until the writer finishes
+    pub fn writer(&self, vod_id: &str) -> io::Result<ArchiveWriter> {
+        fs::create_dir_all(&self.dir)?;
+        let file = NamedTempFile::new_in(&self.dir)?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        encoder.write_all(b"{\"comments\":[")?;
+        Ok(ArchiveWriter {
+            archive: self.clone(),
+            vod_id: vod_id.to_string(),
+            encoder,
+            stream: ArchivedStream {
+                started_at: None,
+                comments: 0,
+                archived_at: None,
+            },
+        })
+    }
+
+    /// Archive a whole chat log
+    pub fn store(&self, vod_id: &str, chat_log: &ChatLog) -> io::Result<()> {
+        let mut writer = self.writer(vod_id)?;
+        for comment in chat_log.comments.iter() {
+            writer.write(comment)?;
+        }
+        writer.finish()
+    }
+
+    fn lookups_path_for(&self, vod_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.lookups.json", vod_id))
+    }
+
+    /// Save the badges and 7TV emotes a VOD was scored with, replacing any saved before
+    pub fn store_lookups(&self, vod_id: &str, lookups: &Lookups) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = NamedTempFile::new_in(&self.dir)?;
+        file.write_all(serde_json::to_string(lookups)?.as_bytes())?;
+        file.persist(self.lookups_path_for(vod_id)).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// The badges and 7TV emotes a VOD was scored with online, if they were saved
+    pub fn read_lookups(&self, vod_id: &str) -> io::Result<Option<Lookups>> {
+        let path = self.lookups_path_for(vod_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+    }
+
+    pub fn read(&self, vod_id: &str) -> io::Result<ChatLog> {
+        let file = File::open(self.path_for(vod_id))?;
+        Ok(serde_json::from_reader(BufReader::new(GzDecoder::new(file)))?)
+    }
+}
+
+pub struct ArchiveWriter {
+    /*
+    Writes a chat log into the archive one comment at a time
+    */
+    archive: ChatArchive,
+    vod_id: String,
+    encoder: GzEncoder<BufWriter<NamedTempFile>>,
+    stream: ArchivedStream,
+}
+
+impl ArchiveWriter {
+    pub fn write(&mut self, comment: &Comment) -> io::Result<()> {
+        if self.stream.comments == 0 {
+            let offset = TimeDelta::milliseconds((comment.content_offset_seconds * 1000.0) as i64);
+            self.stream.started_at = Some(comment.created_at - offset);
+        } else {
+            self.encoder.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.encoder, comment)?;
+        self.stream.comments += 1;
+        Ok(())
+    }
+
+    /// Move the chat log into the archive and add it to the index
+    pub fn finish(mut self) -> io::Result<()> {
+        self.encoder.write_all(b"]}")?;
+        let file = self.encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        file.persist(self.archive.path_for(&self.vod_id))
+            .map_err(|e| e.error)?;
+
+        self.stream.archived_at = Some(Utc::now());
+        let mut index = self.archive.read_index()?;
+        index.insert(self.vod_id.clone(), self.stream);
+        let mut index_file = NamedTempFile::new_in(&self.archive.dir)?;
+        index_file.write_all(serde_json::to_string_pretty(&index)?.as_bytes())?;
+        index_file
+            .persist(self.archive.dir.join(INDEX_FILE))
+            .map_err(|e| e.error)?;
+        info!("Archived the chat log of {}", self.vod_id);
+        Ok(())
+    }
+}
+
+pub struct ArchivedChatSource<'a, S: ChatSource> {
+    /*
+    Reads chat logs from the archive when they are in it. Otherwise they
+    are obtained from the wrapped source and archived as they are read
+    */
+    inner: &'a mut S,
+    archive: ChatArchive,
+}
+
+impl<'a, S: ChatSource> ArchivedChatSource<'a, S> {
+    pub fn new(inner: &'a mut S, archive: ChatArchive) -> Self {
+        Self { inner, archive }
+    }
+}
+
+impl<S: ChatSource> ChatSource for ArchivedChatSource<'_, S> {
+    fn get_name(&self) -> String {
+        format!("{} (archived)", self.inner.get_name())
+    }
+
+    async fn get_chat_log(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<ChatLog, Box<dyn std::error::Error>> {
+        if self.archive.contains(vod_id) {
+            info!("Reading chat log for {} from the archive", vod_id);
+            return Ok(self.archive.read(vod_id)?);
+        }
+        let chat_log = self.inner.get_chat_log(vod_id).await?;
+        self.archive.store(vod_id, &chat_log)?;
+        Ok(chat_log)
+    }
+
+    async fn get_comment_stream(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<CommentStream, Box<dyn std::error::Error>> {
+        if self.archive.contains(vod_id) {
+            info!("Streaming chat log for {} from the archive", vod_id);
+            return Ok(CommentStream::from_gzip_file(self.archive.path_for(vod_id)));
+        }
+
+        let (mut receiver, producer) = self.inner.get_comment_stream(vod_id).await?.into_parts();
+        let mut writer = self.archive.writer(vod_id)?;
+        Ok(CommentStream::from_producer(|sender| async move {
+            let mut count = 0;
+            while let Some(comment) = receiver.recv().await {
+                writer.write(&comment).map_err(|e| e.to_string())?;
+                sender
+                    .send(comment)
+                    .await
+                    .map_err(|_| "Comment receiver was dropped".to_string())?;
+                count += 1;
+            }
+            // Only a chat log that was read to the end is archived
+            producer.await.map_err(|e| e.to_string())?;
+            writer.finish().map_err(|e| e.to_string())?;
+            Ok(count)
+        }))
+    }
+}
@@ -0,0 +1,44 @@
+/*
+Represents somewhere a chat log can be obtained from
+*/
+
+use crate::_types::twitchtypes::ChatLog;
+use crate::chatsources::commentstream::CommentStream;
+
+pub trait ChatSource {
+    /*
+    Structs that implement this trait produce the chat log of a VOD,
+    whether by downloading it or by reading it from disk
+    */
+
+    fn get_name(&self) -> String;
+    /*
+    Name of this chat source, used for logging
+    */
+
+    async fn get_chat_log(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<ChatLog, Box<dyn std::error::Error>>;
+    /*
+    Get the full chat log of a VOD
+
+    :param vod_id: The Twitch VOD ID
+    :return: The chat log, with comments in the order they were sent
+    */
+
+    async fn get_comment_stream(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<CommentStream, Box<dyn std::error::Error>> {
+        /*
+        Get the comments of a VOD as a stream. Sources that can produce
+        comments incrementally should override this so the whole chat log
+        is never held in memory
+
+        :param vod_id: The Twitch VOD ID
+        :return: A stream of comments in the order they were sent
+        */
+        Ok(CommentStream::from_chat_log(self.get_chat_log(vod_id).await?))
+    }
+}
@@ -0,0 +1,134 @@
+/*
+On-disk checkpoint of a partially downloaded chat log, so an interrupted
+download can resume from the last cursor instead of starting over
+*/
+
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::_types::twitchtypes::Comment;
+
+pub struct CommentCheckpoint {
+    /*
+    Comments are appended to `<vod_id>.comments.jsonl` one page at a time,
+    and the cursor of the next page is written to `<vod_id>.cursor` after
+    the page is on disk. A crash between the two writes replays one page,
+    which is why loading deduplicates by comment ID
+    */
+    comments_path: PathBuf,
+    cursor_path: PathBuf,
+}
+
+impl CommentCheckpoint {
+    pub fn new(checkpoint_dir: &Path, vod_id: &str) -> io::Result<Self> {
+        fs::create_dir_all(checkpoint_dir)?;
+        Ok(Self {
+            comments_path: checkpoint_dir.join(format!("{}.comments.jsonl", vod_id)),
+            cursor_path: checkpoint_dir.join(format!("{}.cursor", vod_id)),
+        })
+    }
+
+    /// Load the comments and next cursor of a previous run, if there is one to resume
+    pub fn load(&self) -> io::Result<Option<(Vec<Comment>, String)>> {
+        if !self.cursor_path.exists() || !self.comments_path.exists() {
+            return Ok(None);
+        }
+
+        let cursor = fs::read_to_string(&self.cursor_path)?.trim().to_string();
+        if cursor.is_empty() {
+            return Ok(None);
+        }
+
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut comments: Vec<Comment> = Vec::new();
+        for line in BufReader::new(File::open(&self.comments_path)?).lines() {
+            let line = line?;
+            match serde_json::from_str::<Comment>(&line) {
+                Ok(comment) => {
+                    if seen.insert(comment._id.clone()) {
+                        comments.push(comment);
+                    }
+                }
+                // Only the last line can be torn by a crash mid-write
+                Err(e) => warn!("Skipping unreadable checkpoint line: {}", e),
+            }
+        }
+
+        info!(
+            "Resuming from checkpoint with {} comments already downloaded",
+            comments.len()
+        );
+        Ok(Some((comments, cursor)))
+    }
+
+    /// Append a page of comments, then record the cursor of the page after it
+    pub fn save_page(&self, comments: &[Comment], next_cursor: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.comments_path)?;
+        for comment in comments {
+            serde_json::to_writer(&mut file, comment)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_data()?;
+
+        fs::write(&self.cursor_path, next_cursor)
+    }
+
+    /// Remove the checkpoint once the chat log is complete
+    pub fn clear(&self) -> io::Result<()> {
+        for path in [&self.comments_path, &self.cursor_path] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: &str) -> Comment {
+        serde_json::from_value(serde_json::json!({
+            "_id": id,
+            "created_at": "2024-06-01T18:00:00Z",
+            "content_offset_seconds": 0.0,
+            "message": { "body": "hi", "bits_spent": 0, "fragments": [], "user_badges": null },
+            "commenter": { "display_name": "Alice", "_id": "1001", "logo": "" }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn loads_nothing_without_a_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = CommentCheckpoint::new(dir.path(), "1").unwrap();
+        assert!(checkpoint.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn drops_replayed_comments_and_a_torn_last_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = CommentCheckpoint::new(dir.path(), "1").unwrap();
+        checkpoint.save_page(&[comment("a"), comment("b")], "cursor-b").unwrap();
+        // A crash after the page was written but before its cursor was replays the page
+        checkpoint.save_page(&[comment("b"), comment("c")], "cursor-c").unwrap();
+        checkpoint.save_page(&[comment("c")], "cursor-c").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&checkpoint.comments_path).unwrap();
+        file.write_all(b"{\"_id\":\"d\",\"crea").unwrap();
+
+        let (comments, cursor) = checkpoint.load().unwrap().unwrap();
+        let ids: Vec<&str> = comments.iter().map(|comment| comment._id.as_str()).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(cursor, "cursor-c");
+
+        checkpoint.clear().unwrap();
+        assert!(checkpoint.load().unwrap().is_none());
+    }
+}
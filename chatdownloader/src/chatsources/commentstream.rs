@@ -0,0 +1,165 @@
+/*
+Streams comments out of a chat log one at a time, so a long VOD never has
+to be held in memory as a whole
+*/
+
+use flate2::read::GzDecoder;
+use log::debug;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserializer;
+use std::fmt;
+use std::future::Future;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::_types::twitchtypes::{ChatLog, Comment};
+
+/// How many parsed comments may wait for the processor before the parser blocks
+const STREAM_BUFFER: usize = 1000;
+
+pub struct CommentStream {
+    /*
+    Comments in the order they were sent. The producer runs on its own
+    task; the future from into_parts reports whether it got through
+    the whole chat log
+    */
+    pub receiver: mpsc::Receiver<Comment>,
+    producer: JoinHandle<Result<usize, String>>,
+}
+
+impl CommentStream {
+    /// Stream the comments of a TwitchDownloader chat JSON file. The file is parsed
+    /// incrementally on a blocking thread, and path is dropped once parsing is done,
+    /// so a TempPath can be passed to delete the file afterwards
+    pub fn from_file<P: AsRef<Path> + Send + 'static>(path: P) -> Self {
+        Self::from_decoded_file(path, |file| file)
+    }
+
+    /// Stream the comments of a gzip compressed chat JSON file, like those in the chat archive
+    pub fn from_gzip_file<P: AsRef<Path> + Send + 'static>(path: P) -> Self {
+        Self::from_decoded_file(path, GzDecoder::new)
+    }
+
+    fn from_decoded_file<P, R, F>(path: P, decode: F) -> Self
+    where
+        P: AsRef<Path> + Send + 'static,
+        R: Read,
+        F: FnOnce(File) -> R + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let producer = tokio::task::spawn_blocking(move || {
+            let file = File::open(path.as_ref())
+                .map_err(|e| format!("Could not open {}: {}", path.as_ref().display(), e))?;
+            let mut deserializer =
+                serde_json::Deserializer::from_reader(BufReader::new(decode(file)));
+            let count = deserializer
+                .deserialize_map(ChatLogVisitor { sender: &sender })
+                .and_then(|count| deserializer.end().map(|_| count))
+                .map_err(|e| format!("Could not parse {}: {}", path.as_ref().display(), e))?;
+            debug!("Streamed {} comments from {}", count, path.as_ref().display());
+            Ok(count)
+        });
+        Self { receiver, producer }
+    }
+
+    /// Stream the comments of a chat log that is already in memory
+    pub fn from_chat_log(chat_log: ChatLog) -> Self {
+        Self::from_producer(|sender| async move {
+            let mut count = 0;
+            for comment in chat_log.comments {
+                sender
+                    .send(comment)
+                    .await
+                    .map_err(|_| "Comment receiver was dropped".to_string())?;
+                count += 1;
+            }
+            Ok(count)
+        })
+    }
+
+    /// Stream the comments sent by an async producer. The producer returns how
+    /// many comments it sent, and the stream ends when it returns
+    pub fn from_producer<F, Fut>(producer: F) -> Self
+    where
+        F: FnOnce(mpsc::Sender<Comment>) -> Fut,
+        Fut: Future<Output = Result<usize, String>> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let producer = tokio::spawn(producer(sender));
+        Self { receiver, producer }
+    }
+
+    /// Split the stream into its receiver and a future that resolves once the producer is done
+    pub fn into_parts(
+        self,
+    ) -> (
+        mpsc::Receiver<Comment>,
+        impl Future<Output = Result<usize, Box<dyn std::error::Error>>>,
+    ) {
+        let producer = self.producer;
+        (self.receiver, async move {
+            let count = producer.await??;
+            Ok(count)
+        })
+    }
+}
+
+struct ChatLogVisitor<'a> {
+    sender: &'a mpsc::Sender<Comment>,
+}
+
+impl<'de> Visitor<'de> for ChatLogVisitor<'_> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a chat log object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<usize, A::Error> {
+        let mut count = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "comments" {
+                count = Some(map.next_value_seed(CommentsSeed {
+                    sender: self.sender,
+                })?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        count.ok_or_else(|| de::Error::missing_field("comments"))
+    }
+}
+
+struct CommentsSeed<'a> {
+    sender: &'a mpsc::Sender<Comment>,
+}
+
+impl<'de> DeserializeSeed<'de> for CommentsSeed<'_> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for CommentsSeed<'_> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of comments")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        while let Some(comment) = seq.next_element::<Comment>()? {
+            self.sender
+                .blocking_send(comment)
+                .map_err(|_| de::Error::custom("comment receiver was dropped"))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
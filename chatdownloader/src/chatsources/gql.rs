@@ -0,0 +1,557 @@
+/*
+Downloads VOD comments directly from Twitch's GraphQL API
+*/
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use regex::Regex;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::_constants::USER_AGENT;
+use crate::_types::twitchtypes::{
+    Badge, ChatLog, ChatMessage, ChatMessageFragment, ChatMessageFragmentEmoticon, ChatUserInfo,
+    Comment,
+};
+use crate::chatsources::checkpoint::CommentCheckpoint;
+use crate::chatsources::chatsourcetrait::ChatSource;
+
+pub const GQL_URL: &str = "https://gql.twitch.tv/gql";
+/// The public client ID used by the Twitch website, which is what the comments query expects
+const GQL_CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
+const COMMENTS_QUERY_HASH: &str =
+    "b70a3591ff0f4e0313d126c6a1502d79a1c02baebb288227c582044aa76adf6a";
+
+const MAX_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref CHEER_REGEX: Regex = Regex::new(
+        r"(?i)^(?:cheer|biblethump|cheerwhal|corgo|uni|showlove|party|seemsgood|pride|kappa|frankerz|heyguys|dansgame|elegiggle|trihard|kreygasm|4head|swiftrage|notlikethis|failfish|vohiyo|pjsalt|mrdestructoid|bday|ripcheer|shamrock)(?P<bits>[0-9]+)$"
+    ).unwrap();
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GqlEmote {
+    #[serde(rename = "emoteID")]
+    emote_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GqlFragment {
+    text: String,
+    emote: Option<GqlEmote>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GqlBadge {
+    #[serde(rename = "setID")]
+    set_id: String,
+    version: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GqlMessage {
+    fragments: Vec<GqlFragment>,
+    user_badges: Option<Vec<GqlBadge>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GqlCommenter {
+    id: String,
+    display_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GqlCommentNode {
+    id: String,
+    created_at: DateTime<Utc>,
+    content_offset_seconds: f64,
+    commenter: Option<GqlCommenter>,
+    message: GqlMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct GqlCommentEdge {
+    cursor: Option<String>,
+    node: GqlCommentNode,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GqlPageInfo {
+    has_next_page: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GqlComments {
+    edges: Vec<GqlCommentEdge>,
+    page_info: GqlPageInfo,
+}
+
+#[derive(Deserialize, Debug)]
+struct GqlVideo {
+    comments: Option<GqlComments>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GqlData {
+    video: Option<GqlVideo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GqlResponse {
+    data: Option<GqlData>,
+}
+
+pub struct GqlChatSource {
+    client: reqwest::Client,
+    endpoint: String,
+    checkpoint_dir: Option<PathBuf>,
+    /// How long to wait before the first retry. It doubles with every retry, up to max_backoff
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl GqlChatSource {
+    /// Create a new source. endpoint can point at a local mock server, and
+    /// downloads are checkpointed to checkpoint_dir when one is given
+    pub fn new(endpoint: String, checkpoint_dir: Option<PathBuf>) -> Self {
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(USER_AGENT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to create HTTP Client");
+        Self {
+            client,
+            endpoint,
+            checkpoint_dir,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+        }
+    }
+
+    /// Fetch a page, retrying network errors and server errors with exponential
+    /// backoff. Rate limited responses wait for Retry-After when Twitch sends it
+    async fn fetch_page_with_retries(
+        &self,
+        vod_id: &str,
+        cursor: Option<&str>,
+    ) -> Result<GqlComments, Box<dyn std::error::Error>> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let wait = match self.fetch_page(vod_id, cursor).await {
+                Ok(page) => return Ok(page),
+                Err(PageError::Fatal(e)) => return Err(e),
+                Err(PageError::Retryable(e)) | Err(PageError::RateLimited(e, _))
+                    if attempt >= MAX_ATTEMPTS =>
+                {
+                    return Err(format!("Giving up after {} attempts: {}", attempt, e).into());
+                }
+                Err(PageError::RateLimited(e, retry_after)) => {
+                    warn!("Rate limited by the GraphQL API: {}", e);
+                    retry_after.unwrap_or(backoff)
+                }
+                Err(PageError::Retryable(e)) => {
+                    warn!("Failed to fetch comment page (attempt {}): {}", attempt, e);
+                    backoff
+                }
+            };
+            debug!("Retrying in {:?}", wait);
+            sleep(wait).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    async fn fetch_page(&self, vod_id: &str, cursor: Option<&str>) -> Result<GqlComments, PageError> {
+        let variables = match cursor {
+            Some(cursor) => json!({ "videoID": vod_id, "cursor": cursor }),
+            None => json!({ "videoID": vod_id, "contentOffsetSeconds": 0 }),
+        };
+        let body = json!([{
+            "operationName": "VideoCommentsByOffsetOrCursor",
+            "variables": variables,
+            "extensions": {
+                "persistedQuery": {
+                    "version": 1,
+                    "sha256Hash": COMMENTS_QUERY_HASH,
+                }
+            }
+        }]);
+
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .header("Client-ID", GQL_CLIENT_ID)
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| PageError::Retryable(e.into()))?;
+
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok())
+                .map(Duration::from_secs);
+            return Err(PageError::RateLimited(status.to_string().into(), retry_after));
+        }
+        if status.is_server_error() {
+            return Err(PageError::Retryable(status.to_string().into()));
+        }
+        if !status.is_success() {
+            return Err(PageError::Fatal(
+                format!("GraphQL API returned {}", status).into(),
+            ));
+        }
+
+        let mut responses: Vec<GqlResponse> = response
+            .json()
+            .await
+            .map_err(|e| PageError::Retryable(e.into()))?;
+        responses
+            .pop()
+            .and_then(|response| response.data)
+            .and_then(|data| data.video)
+            .and_then(|video| video.comments)
+            .ok_or_else(|| PageError::Fatal(format!("No comments returned for VOD {}", vod_id).into()))
+    }
+}
+
+impl ChatSource for GqlChatSource {
+    fn get_name(&self) -> String {
+        "gql".to_string()
+    }
+
+    async fn get_chat_log(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<ChatLog, Box<dyn std::error::Error>> {
+        info!("Downloading comments for {} from the GraphQL API", vod_id);
+        let checkpoint = match &self.checkpoint_dir {
+            Some(dir) => Some(CommentCheckpoint::new(dir, vod_id)?),
+            None => None,
+        };
+
+        let resumed = match &checkpoint {
+            Some(checkpoint) => checkpoint.load()?,
+            None => None,
+        };
+        let (mut comments, mut cursor) = match resumed {
+            Some((comments, cursor)) => (comments, Some(cursor)),
+            None => {
+                if let Some(checkpoint) = &checkpoint {
+                    checkpoint.clear()?;
+                }
+                (Vec::new(), None)
+            }
+        };
+
+        loop {
+            let page = self.fetch_page_with_retries(vod_id, cursor.as_deref()).await?;
+            cursor = page.edges.last().and_then(|edge| edge.cursor.clone());
+            let page_comments: Vec<Comment> =
+                page.edges.into_iter().filter_map(edge_to_comment).collect();
+            debug!("Downloaded {} comments so far", comments.len() + page_comments.len());
+
+            let has_next_page = page.page_info.has_next_page && cursor.is_some();
+            if let (Some(checkpoint), Some(next_cursor), true) = (&checkpoint, &cursor, has_next_page) {
+                checkpoint.save_page(&page_comments, next_cursor)?;
+            }
+            comments.extend(page_comments);
+
+            if !has_next_page {
+                break;
+            }
+        }
+
+        if let Some(checkpoint) = &checkpoint {
+            checkpoint.clear()?;
+        }
+
+        info!("Downloaded {} comments for {}", comments.len(), vod_id);
+        Ok(ChatLog { comments })
+    }
+}
+
+enum PageError {
+    /// A failure that will not go away by retrying, e.g. an unknown VOD
+    Fatal(Box<dyn std::error::Error>),
+    /// A network error or a 5xx response
+    Retryable(Box<dyn std::error::Error>),
+    /// A 429 response, with the Retry-After delay if Twitch sent one
+    RateLimited(Box<dyn std::error::Error>, Option<Duration>),
+}
+
+/// Convert a GraphQL comment into the TwitchDownloader shape. Comments from deleted users are dropped
+fn edge_to_comment(edge: GqlCommentEdge) -> Option<Comment> {
+    let node = edge.node;
+    let commenter = node.commenter?;
+
+    let fragments: Vec<ChatMessageFragment> = node
+        .message
+        .fragments
+        .into_iter()
+        .map(|fragment| ChatMessageFragment {
+            text: fragment.text,
+            emoticon: fragment
+                .emote
+                .and_then(|emote| emote.emote_id)
+                .map(|emoticon_id| ChatMessageFragmentEmoticon { emoticon_id }),
+        })
+        .collect();
+
+    let body: String = fragments.iter().map(|fragment| fragment.text.as_str()).collect();
+    let bits_spent = count_bits(&body);
+
+    let user_badges = node.message.user_badges.map(|badges| {
+        badges
+            .into_iter()
+            .map(|badge| Badge {
+                _id: badge.set_id,
+                version: badge.version,
+            })
+            .collect()
+    });
+
+    Some(Comment {
+        _id: node.id,
+        created_at: node.created_at,
+        content_offset_seconds: node.content_offset_seconds,
+        message: ChatMessage {
+            body,
+            bits_spent,
+            fragments,
+            user_badges,
+        },
+        commenter: ChatUserInfo {
+            display_name: commenter.display_name,
+            _id: commenter.id,
+            logo: String::new(),
+        },
+    })
+}
+
+/// Sum the cheermotes (e.g. "Cheer100") in a message, as the GraphQL API does not report bits directly
+fn count_bits(body: &str) -> u32 {
+    body.split_whitespace()
+        .filter_map(|word| CHEER_REGEX.captures(word))
+        .filter_map(|caps| caps.name("bits")?.as_str().parse::<u32>().ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, HeaderValue};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    const PAGE_1: &str = include_str!("../../tests/fixtures/gql/page1.json");
+    const PAGE_2: &str = include_str!("../../tests/fixtures/gql/page2.json");
+    const PAGE_3: &str = include_str!("../../tests/fixtures/gql/page3.json");
+    const VOD_ID: &str = "2175349344";
+
+    /// What the mock server answers a request with
+    #[derive(Clone)]
+    enum Reply {
+        /// A recorded page of comments
+        Page(&'static str),
+        /// An error status, with a Retry-After header if given
+        Status(u16, Option<&'static str>),
+    }
+
+    #[derive(Default)]
+    struct MockGql {
+        /// Replies to give in order. The last one is repeated once the others are used up
+        replies: Mutex<VecDeque<Reply>>,
+        /// The variables of every request received
+        requests: Mutex<Vec<serde_json::Value>>,
+    }
+
+    async fn answer(State(mock): State<Arc<MockGql>>, Json(body): Json<serde_json::Value>) -> Response {
+        mock.requests.lock().unwrap().push(body[0]["variables"].clone());
+        let reply = {
+            let mut replies = mock.replies.lock().unwrap();
+            match replies.len() {
+                1 => replies[0].clone(),
+                _ => replies.pop_front().expect("the mock server has no replies"),
+            }
+        };
+        match reply {
+            Reply::Page(page) => {
+                let mut headers = HeaderMap::new();
+                headers.insert("content-type", HeaderValue::from_static("application/json"));
+                (headers, page).into_response()
+            }
+            Reply::Status(status, retry_after) => {
+                let mut headers = HeaderMap::new();
+                if let Some(retry_after) = retry_after {
+                    headers.insert("retry-after", HeaderValue::from_static(retry_after));
+                }
+                (axum::http::StatusCode::from_u16(status).unwrap(), headers).into_response()
+            }
+        }
+    }
+
+    /// Serve the replies on a local port, returning the endpoint and the mock's state
+    async fn serve(replies: Vec<Reply>) -> (String, Arc<MockGql>) {
+        let mock = Arc::new(MockGql {
+            replies: Mutex::new(replies.into()),
+            requests: Mutex::new(Vec::new()),
+        });
+        let app = Router::new().route("/gql", post(answer)).with_state(mock.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/gql", addr), mock)
+    }
+
+    /// A source that retries quickly, so the tests do not wait for the real backoff
+    fn source(endpoint: String, checkpoint_dir: Option<PathBuf>) -> GqlChatSource {
+        let mut source = GqlChatSource::new(endpoint, checkpoint_dir);
+        source.initial_backoff = Duration::from_millis(10);
+        source.max_backoff = Duration::from_millis(40);
+        source
+    }
+
+    fn ids(chat_log: &ChatLog) -> Vec<&str> {
+        chat_log.comments.iter().map(|comment| &comment._id[..5]).collect()
+    }
+
+    #[tokio::test]
+    async fn follows_cursors_until_the_last_page() {
+        let (endpoint, mock) =
+            serve(vec![Reply::Page(PAGE_1), Reply::Page(PAGE_2), Reply::Page(PAGE_3)]).await;
+        let chat_log = source(endpoint, None).get_chat_log(VOD_ID).await.unwrap();
+
+        // The comment of the deleted user is dropped
+        assert_eq!(ids(&chat_log), ["c0001", "c0002", "c0004", "c0005", "c0006"]);
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], json!({ "videoID": VOD_ID, "contentOffsetSeconds": 0 }));
+        assert_eq!(requests[1]["cursor"], "eyJpZCI6ImMwMDAyIn0=");
+        assert_eq!(requests[2]["cursor"], "eyJpZCI6ImMwMDA0In0=");
+    }
+
+    #[tokio::test]
+    async fn converts_comments_to_the_twitchdownloader_shape() {
+        let (endpoint, _) = serve(vec![Reply::Page(PAGE_1), Reply::Page(PAGE_2), Reply::Page(PAGE_3)]).await;
+        let chat_log = source(endpoint, None).get_chat_log(VOD_ID).await.unwrap();
+
+        let cheer = &chat_log.comments[1];
+        assert_eq!(cheer.commenter.display_name, "Bob");
+        assert_eq!(cheer.message.bits_spent, 150);
+        let emote = &chat_log.comments[2];
+        assert_eq!(emote.message.body, "neuroWave neuroWave");
+        assert_eq!(
+            emote.message.fragments[1].emoticon.as_ref().unwrap().emoticon_id,
+            "emotesv2_1234"
+        );
+        let badges = emote.message.user_badges.as_ref().unwrap();
+        assert_eq!((badges[0]._id.as_str(), badges[0].version.as_str()), ("subscriber", "0"));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_backoff() {
+        let (endpoint, mock) = serve(vec![
+            Reply::Status(500, None),
+            Reply::Status(503, None),
+            Reply::Page(PAGE_3),
+        ])
+        .await;
+        let started = Instant::now();
+        let chat_log = source(endpoint, None).get_chat_log(VOD_ID).await.unwrap();
+
+        assert_eq!(ids(&chat_log), ["c0005", "c0006"]);
+        assert_eq!(mock.requests.lock().unwrap().len(), 3);
+        // 10ms before the first retry, then 20ms
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (endpoint, mock) = serve(vec![Reply::Status(502, None)]).await;
+        let error = source(endpoint, None).get_chat_log(VOD_ID).await.unwrap_err();
+
+        assert!(error.to_string().starts_with("Giving up after 8 attempts"));
+        assert_eq!(mock.requests.lock().unwrap().len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (endpoint, mock) = serve(vec![Reply::Status(404, None)]).await;
+        let error = source(endpoint, None).get_chat_log(VOD_ID).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "GraphQL API returned 404 Not Found");
+        assert_eq!(mock.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn waits_for_retry_after_when_rate_limited() {
+        let (endpoint, mock) =
+            serve(vec![Reply::Status(429, Some("1")), Reply::Page(PAGE_3)]).await;
+        let mut source = source(endpoint, None);
+        // Retry-After must win over the backoff, or the test takes ten seconds
+        source.initial_backoff = Duration::from_secs(10);
+        let started = Instant::now();
+        source.get_chat_log(VOD_ID).await.unwrap();
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "retried after {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "retried after {:?}", elapsed);
+        assert_eq!(mock.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn resumes_an_interrupted_download_from_the_checkpoint() {
+        let checkpoint_dir = tempfile::tempdir().unwrap();
+        let checkpoint_path = Some(checkpoint_dir.path().to_path_buf());
+
+        // The download fails for good after two pages
+        let (endpoint, _) = serve(vec![
+            Reply::Page(PAGE_1),
+            Reply::Page(PAGE_2),
+            Reply::Status(400, None),
+        ])
+        .await;
+        source(endpoint, checkpoint_path.clone())
+            .get_chat_log(VOD_ID)
+            .await
+            .unwrap_err();
+
+        let (endpoint, mock) = serve(vec![Reply::Page(PAGE_3)]).await;
+        let chat_log = source(endpoint, checkpoint_path).get_chat_log(VOD_ID).await.unwrap();
+
+        assert_eq!(ids(&chat_log), ["c0001", "c0002", "c0004", "c0005", "c0006"]);
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["cursor"], "eyJpZCI6ImMwMDA0In0=");
+        // A finished download leaves no checkpoint behind
+        assert_eq!(fs::read_dir(checkpoint_dir.path()).unwrap().count(), 0);
+    }
+}
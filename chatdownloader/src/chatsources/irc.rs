@@ -0,0 +1,636 @@
+/*
+Reads chat live from Twitch IRC and converts it into TwitchDownloader comments
+*/
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::_types::twitchtypes::{
+    Badge, ChatMessage, ChatMessageFragment, ChatMessageFragmentEmoticon, ChatUserInfo, Comment,
+};
+use crate::chatsources::commentstream::CommentStream;
+
+pub const TWITCH_IRC_ADDR: &str = "irc.chat.twitch.tv:6667";
+/// Anonymous logins need no OAuth token, and can read any channel
+const ANONYMOUS_NICK: &str = "justinfan73921";
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How many times in a row connecting may fail before the session ends with the comments so far
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Why a connection ended
+enum Disconnect {
+    /// Ctrl-C was pressed
+    Interrupted,
+    /// The server asked us to reconnect
+    Reconnect,
+    /// The connection could not be made, broke or was closed by the server,
+    /// e.g. after a network outage or a server restart
+    Lost(String),
+}
+
+/// Stream comments from a channel's chat until Ctrl-C is pressed or the connection cannot be restored
+/// Comment offsets are measured from when the session started, as IRC does not know when the stream did
+pub fn live_comment_stream(addr: String, channel_login: String) -> CommentStream {
+    stream_chat(addr, channel_login, INITIAL_BACKOFF, MAX_BACKOFF)
+}
+
+/// Stream the chat, reconnecting when the connection is lost. The wait before reconnecting starts
+/// at initial_backoff and doubles with every failed attempt, up to max_backoff. If reconnecting
+/// keeps failing the session ends, keeping the comments read so far
+fn stream_chat(
+    addr: String,
+    channel_login: String,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+) -> CommentStream {
+    CommentStream::from_producer(move |sender| async move {
+        let started_at = Utc::now();
+        let mut count = 0;
+        let mut failures = 0;
+        let mut backoff = initial_backoff;
+        loop {
+            let disconnect = tokio::select! {
+                result = read_chat(&addr, &channel_login, started_at, &sender, &mut count, &mut failures) => result?,
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Interrupted, ending the live session");
+                    Disconnect::Interrupted
+                }
+            };
+            match disconnect {
+                Disconnect::Interrupted => break,
+                Disconnect::Reconnect => {
+                    info!("Server asked us to reconnect");
+                    backoff = initial_backoff;
+                }
+                Disconnect::Lost(e) => {
+                    if failures == 0 {
+                        backoff = initial_backoff;
+                    }
+                    failures += 1;
+                    if failures > MAX_RECONNECT_ATTEMPTS {
+                        error!(
+                            "{}. Giving up after {} attempts to reconnect",
+                            e, MAX_RECONNECT_ATTEMPTS
+                        );
+                        break;
+                    }
+                    warn!("{}. Reconnecting in {:?}", e, backoff);
+                    tokio::select! {
+                        _ = sleep(backoff) => {}
+                        _ = tokio::signal::ctrl_c() => {
+                            info!("Interrupted, ending the live session");
+                            break;
+                        }
+                    }
+                    backoff = (backoff * 2).min(max_backoff);
+                }
+            }
+        }
+        info!("Live session ended after {} comments", count);
+        Ok(count)
+    })
+}
+
+/// Read one IRC connection. Only fails if the comments can no longer be handed over. failures is
+/// reset once the server sends anything, so only failed attempts in a row count
+async fn read_chat(
+    addr: &str,
+    channel_login: &str,
+    started_at: DateTime<Utc>,
+    sender: &mpsc::Sender<Comment>,
+    count: &mut usize,
+    failures: &mut u32,
+) -> Result<Disconnect, String> {
+    info!("Connecting to {} for #{}", addr, channel_login);
+    let stream = match TcpStream::connect(addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            return Ok(Disconnect::Lost(format!(
+                "Could not connect to {}: {}",
+                addr, e
+            )))
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+
+    let login = format!(
+        "CAP REQ :twitch.tv/tags twitch.tv/commands\r\nPASS SCHMOOPIIE\r\nNICK {}\r\nJOIN #{}\r\n",
+        ANONYMOUS_NICK,
+        channel_login.to_lowercase()
+    );
+    if let Err(e) = writer.write_all(login.as_bytes()).await {
+        return Ok(Disconnect::Lost(format!("Could not log in: {}", e)));
+    }
+
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                return Ok(Disconnect::Lost(format!(
+                    "Connection to {} was closed",
+                    addr
+                )))
+            }
+            Err(e) => {
+                return Ok(Disconnect::Lost(format!(
+                    "Lost connection to {}: {}",
+                    addr, e
+                )))
+            }
+        };
+        *failures = 0;
+        let Some(message) = IrcMessage::parse(&line) else {
+            debug!("Ignoring unparsable line: {}", line);
+            continue;
+        };
+
+        match message.command.as_str() {
+            "PING" => {
+                let pong = format!("PONG :{}\r\n", message.trailing.unwrap_or_default());
+                if let Err(e) = writer.write_all(pong.as_bytes()).await {
+                    return Ok(Disconnect::Lost(format!("Could not answer PING: {}", e)));
+                }
+            }
+            "RECONNECT" => return Ok(Disconnect::Reconnect),
+            "PRIVMSG" | "USERNOTICE" => {
+                if let Some(comment) = message_to_comment(&message, channel_login, started_at) {
+                    sender
+                        .send(comment)
+                        .await
+                        .map_err(|_| "Comment receiver was dropped".to_string())?;
+                    *count += 1;
+                }
+            }
+            "NOTICE" => warn!(
+                "Notice from Twitch: {}",
+                message.trailing.unwrap_or_default()
+            ),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct IrcMessage {
+    tags: HashMap<String, String>,
+    command: String,
+    trailing: Option<String>,
+}
+
+impl IrcMessage {
+    /// Parse a line like `@tag=value;tag2=value :prefix COMMAND #channel :trailing`
+    fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut message = IrcMessage::default();
+
+        if let Some(stripped) = rest.strip_prefix('@') {
+            let (tags, remainder) = stripped.split_once(' ')?;
+            message.tags = tags
+                .split(';')
+                .map(|tag| {
+                    let (key, value) = tag.split_once('=').unwrap_or((tag, ""));
+                    (key.to_string(), unescape_tag_value(value))
+                })
+                .collect();
+            rest = remainder;
+        }
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ').map(|(_, remainder)| remainder)?;
+        }
+
+        let (params, trailing) = match rest.split_once(" :") {
+            Some((params, trailing)) => (params, Some(trailing.to_string())),
+            None => (rest, None),
+        };
+        message.command = params.split(' ').next()?.to_string();
+        message.trailing = trailing;
+        Some(message)
+    }
+
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .get(key)
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => out.push(' '),
+            Some(':') => out.push(';'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Convert a PRIVMSG or USERNOTICE into a comment, in the same shape TwitchDownloader produces
+fn message_to_comment(
+    message: &IrcMessage,
+    channel_login: &str,
+    started_at: DateTime<Utc>,
+) -> Option<Comment> {
+    let user_id = message.tag("user-id")?.to_string();
+    let display_name = message
+        .tag("display-name")
+        .or(message.tag("login"))
+        .unwrap_or(&user_id)
+        .to_string();
+
+    let body = if message.command == "USERNOTICE" {
+        usernotice_body(message, &display_name, channel_login)?
+    } else {
+        message.trailing.clone().unwrap_or_default()
+    };
+    // Emote positions only refer to the user's own text
+    let fragments = if message.command == "PRIVMSG" {
+        split_fragments(&body, message.tag("emotes"))
+    } else {
+        split_fragments(&body, None)
+    };
+
+    let user_badges = message.tag("badges").map(|badges| {
+        badges
+            .split(',')
+            .filter_map(|badge| badge.split_once('/'))
+            .map(|(id, version)| Badge {
+                _id: id.to_string(),
+                version: version.to_string(),
+            })
+            .collect()
+    });
+
+    // tmi-sent-ts is when Twitch received the message, in milliseconds since the epoch
+    let created_at = message
+        .tag("tmi-sent-ts")
+        .and_then(|sent| sent.parse().ok())
+        .and_then(DateTime::from_timestamp_millis)
+        .unwrap_or_else(Utc::now);
+    let content_offset_seconds =
+        ((created_at - started_at).num_milliseconds() as f64 / 1000.0).max(0.0);
+
+    Some(Comment {
+        _id: message.tag("id").unwrap_or_default().to_string(),
+        created_at,
+        content_offset_seconds,
+        message: ChatMessage {
+            body,
+            bits_spent: message
+                .tag("bits")
+                .and_then(|bits| bits.parse().ok())
+                .unwrap_or(0),
+            fragments,
+            user_badges,
+        },
+        commenter: ChatUserInfo {
+            display_name,
+            _id: user_id,
+            logo: String::new(),
+        },
+    })
+}
+
+/// The text TwitchDownloader shows for a notice. Gift subs are worded the way the subs metric expects
+fn usernotice_body(
+    message: &IrcMessage,
+    display_name: &str,
+    channel_login: &str,
+) -> Option<String> {
+    let tier = match message.tag("msg-param-sub-plan") {
+        Some("2000") => 2,
+        Some("3000") => 3,
+        _ => 1,
+    };
+    match message.tag("msg-id") {
+        Some("subgift") | Some("anonsubgift") => Some(format!(
+            "{} gifted a Tier {} Sub to {}!",
+            display_name,
+            tier,
+            message.tag("msg-param-recipient-display-name")?
+        )),
+        Some("submysterygift") | Some("anonsubmysterygift") => Some(format!(
+            "{} is gifting {} Tier {} Subs to {}'s community!",
+            display_name,
+            message.tag("msg-param-mass-gift-count")?,
+            tier,
+            channel_login
+        )),
+        _ => message
+            .trailing
+            .clone()
+            .or_else(|| message.tag("system-msg").map(str::to_string)),
+    }
+}
+
+/// Split a message into text and emote fragments using the `emotes` tag,
+/// whose ranges are inclusive character offsets, e.g. `25:0-4,12-16/1902:6-10`
+fn split_fragments(body: &str, emotes: Option<&str>) -> Vec<ChatMessageFragment> {
+    let mut ranges: Vec<(usize, usize, String)> = emotes
+        .unwrap_or_default()
+        .split('/')
+        .filter_map(|emote| emote.split_once(':'))
+        .flat_map(|(emote_id, positions)| {
+            positions.split(',').filter_map(move |position| {
+                let (start, end) = position.split_once('-')?;
+                Some((start.parse().ok()?, end.parse().ok()?, emote_id.to_string()))
+            })
+        })
+        .collect();
+    ranges.sort_by_key(|(start, _, _)| *start);
+
+    let chars: Vec<char> = body.chars().collect();
+    let mut fragments = Vec::new();
+    let mut position = 0;
+    for (start, end, emote_id) in ranges {
+        // The tag comes from the server, so overlapping, reversed or out of range ranges are skipped
+        if start < position || start > end || end >= chars.len() {
+            continue;
+        }
+        if start > position {
+            fragments.push(ChatMessageFragment {
+                text: chars[position..start].iter().collect(),
+                emoticon: None,
+            });
+        }
+        fragments.push(ChatMessageFragment {
+            text: chars[start..=end].iter().collect(),
+            emoticon: Some(ChatMessageFragmentEmoticon {
+                emoticon_id: emote_id,
+            }),
+        });
+        position = end + 1;
+    }
+    if position < chars.len() || fragments.is_empty() {
+        fragments.push(ChatMessageFragment {
+            text: chars[position..].iter().collect(),
+            emoticon: None,
+        });
+    }
+    fragments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A chat session recorded from the server's side. The server asks for a reconnect after
+    /// Carol's message, and the connection breaks after the second PING
+    const SESSION: &str = include_str!("../../tests/fixtures/irc/session.log");
+
+    /// How the fake server ends a connection once it has replayed its lines
+    enum End {
+        /// Wait for the client to hang up, as it should after a RECONNECT
+        AwaitHangUp,
+        /// Abort the connection, like a dropped network would
+        Reset,
+        /// Close the connection normally, as a restarting server would
+        Close,
+    }
+
+    /// Serve one connection per (lines, end) pair. Every PING waits for the client to answer it.
+    /// Resolves to the lines the client sent. Connecting fails once every connection was served
+    async fn fake_server(
+        connections: Vec<(Vec<&'static str>, End)>,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for (lines, end) in connections {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader).lines();
+                // The login ends with JOIN
+                while let Some(line) = reader.next_line().await.unwrap() {
+                    let joined = line.starts_with("JOIN");
+                    received.push(line);
+                    if joined {
+                        break;
+                    }
+                }
+                for line in lines {
+                    writer
+                        .write_all(format!("{}\r\n", line).as_bytes())
+                        .await
+                        .unwrap();
+                    if line.starts_with("PING") {
+                        received.push(reader.next_line().await.unwrap().unwrap());
+                    }
+                }
+                match end {
+                    End::AwaitHangUp => {
+                        while let Some(line) = reader.next_line().await.unwrap() {
+                            received.push(line);
+                        }
+                    }
+                    End::Reset => {
+                        let stream = reader.into_inner().into_inner().reunite(writer).unwrap();
+                        #[allow(deprecated)]
+                        stream.set_linger(Some(Duration::ZERO)).unwrap();
+                    }
+                    End::Close => {}
+                }
+            }
+            received
+        });
+        (addr, server)
+    }
+
+    async fn collect(stream: CommentStream) -> (Vec<Comment>, Result<usize, String>) {
+        let (mut receiver, producer) = stream.into_parts();
+        let mut comments = Vec::new();
+        while let Some(comment) = receiver.recv().await {
+            comments.push(comment);
+        }
+        (comments, producer.await.map_err(|e| e.to_string()))
+    }
+
+    #[tokio::test]
+    async fn replays_a_session_across_reconnects() {
+        let lines: Vec<&str> = SESSION.lines().collect();
+        let reconnect = lines
+            .iter()
+            .position(|line| line.ends_with("RECONNECT"))
+            .unwrap()
+            + 1;
+        let cut = reconnect
+            + lines[reconnect..]
+                .iter()
+                .position(|line| line.starts_with("PING"))
+                .unwrap()
+            + 1;
+        let (addr, server) = fake_server(vec![
+            (lines[..reconnect].to_vec(), End::AwaitHangUp),
+            (lines[reconnect..cut].to_vec(), End::Reset),
+            (lines[cut..].to_vec(), End::Close),
+        ])
+        .await;
+
+        let stream = stream_chat(
+            addr,
+            "SomeChannel".to_string(),
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+        );
+        let (comments, result) = collect(stream).await;
+        assert_eq!(result, Ok(7));
+
+        let names: Vec<&str> = comments
+            .iter()
+            .map(|c| c.commenter.display_name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["Alice", "Bob", "Carol", "Dave", "Erin", "Gina", "Hank"]
+        );
+        assert_eq!(comments[0]._id, "7c1e0a52-0001-4d1b-9a51-3f0c2d6a0001");
+        assert_eq!(comments[0].commenter._id, "1001");
+        let badges = comments[0].message.user_badges.as_ref().unwrap();
+        assert_eq!(
+            (badges[0]._id.as_str(), badges[0].version.as_str()),
+            ("subscriber", "12")
+        );
+        assert_eq!(comments[1].message.bits_spent, 100);
+        assert_eq!(
+            comments[4].message.body,
+            "Erin gifted a Tier 1 Sub to Frank!"
+        );
+
+        let received = server.await.unwrap();
+        let joins = received
+            .iter()
+            .filter(|line| *line == "JOIN #somechannel")
+            .count();
+        let pongs = received
+            .iter()
+            .filter(|line| *line == "PONG :tmi.twitch.tv")
+            .count();
+        assert_eq!((joins, pongs), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_the_server_closes_the_connection() {
+        let lines: Vec<&str> = SESSION.lines().collect();
+        let reconnect = lines
+            .iter()
+            .position(|line| line.ends_with("RECONNECT"))
+            .unwrap();
+        let (addr, server) = fake_server(vec![
+            (lines[..reconnect].to_vec(), End::Close),
+            (lines[reconnect + 1..].to_vec(), End::Close),
+        ])
+        .await;
+
+        let stream = stream_chat(
+            addr,
+            "somechannel".to_string(),
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+        );
+        let (comments, result) = collect(stream).await;
+        assert_eq!(result, Ok(7));
+        assert_eq!(comments[3].commenter.display_name, "Dave");
+
+        let received = server.await.unwrap();
+        let joins = received
+            .iter()
+            .filter(|line| *line == "JOIN #somechannel")
+            .count();
+        assert_eq!(joins, 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_malformed_emote_tag_as_text() {
+        let lines: Vec<&str> = SESSION.lines().collect();
+        let (addr, _server) = fake_server(vec![(lines[..10].to_vec(), End::Close)]).await;
+        let stream = stream_chat(
+            addr,
+            "somechannel".to_string(),
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+        );
+        let (comments, result) = collect(stream).await;
+        assert_eq!(result, Ok(3));
+
+        let emotes: Vec<(&str, Option<&str>)> = comments[0]
+            .message
+            .fragments
+            .iter()
+            .map(|f| {
+                (
+                    f.text.as_str(),
+                    f.emoticon.as_ref().map(|e| e.emoticon_id.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            emotes,
+            [
+                ("Kappa", Some("25")),
+                (" hello ", None),
+                ("Kappa", Some("25"))
+            ]
+        );
+
+        let carol = &comments[2].message.fragments;
+        assert_eq!(carol.len(), 1);
+        assert_eq!(carol[0].text, "hello :) there");
+        assert!(carol[0].emoticon.is_none());
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_the_server_stays_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let stream = stream_chat(
+            addr,
+            "somechannel".to_string(),
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+        );
+        let (comments, result) = collect(stream).await;
+        assert!(comments.is_empty());
+        assert_eq!(result, Ok(0));
+    }
+
+    #[test]
+    fn skips_invalid_emote_ranges() {
+        let texts = |emotes: &str| -> Vec<String> {
+            split_fragments("Kappa hi", Some(emotes))
+                .into_iter()
+                .map(|f| f.text)
+                .collect()
+        };
+        assert_eq!(texts("25:0-4"), ["Kappa", " hi"]);
+        // Reversed, out of range, overlapping and unparsable ranges
+        assert_eq!(texts("25:4-0"), ["Kappa hi"]);
+        assert_eq!(texts("25:6-20"), ["Kappa hi"]);
+        assert_eq!(texts("25:0-4/1:2-6"), ["Kappa", " hi"]);
+        assert_eq!(texts("25:a-4"), ["Kappa hi"]);
+    }
+}
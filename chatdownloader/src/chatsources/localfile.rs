@@ -0,0 +1,63 @@
+/*
+Reads chat logs that were already downloaded to disk
+*/
+
+use log::info;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::_types::twitchtypes::ChatLog;
+use crate::chatsources::chatsourcetrait::ChatSource;
+use crate::chatsources::commentstream::CommentStream;
+
+pub struct LocalChatSource {
+    /*
+    Either a single TwitchDownloader chat JSON, used for every VOD ID,
+    or a directory containing one `<vod_id>.json` per VOD
+    */
+    path: PathBuf,
+}
+
+impl LocalChatSource {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn path_for(&self, vod_id: &str) -> PathBuf {
+        if self.path.is_dir() {
+            self.path.join(format!("{}.json", vod_id))
+        } else {
+            self.path.clone()
+        }
+    }
+}
+
+impl ChatSource for LocalChatSource {
+    fn get_name(&self) -> String {
+        "local".to_string()
+    }
+
+    async fn get_chat_log(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<ChatLog, Box<dyn std::error::Error>> {
+        let path = self.path_for(vod_id);
+        info!("Reading chat log for {} from {}", vod_id, path.display());
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read chat log {}: {}", path.display(), e))?;
+        let chat_log: ChatLog = serde_json::from_str(&contents)?;
+        Ok(chat_log)
+    }
+
+    async fn get_comment_stream(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<CommentStream, Box<dyn std::error::Error>> {
+        let path = self.path_for(vod_id);
+        if !path.is_file() {
+            return Err(format!("Chat log {} does not exist", path.display()).into());
+        }
+        info!("Streaming chat log for {} from {}", vod_id, path.display());
+        Ok(CommentStream::from_file(path))
+    }
+}
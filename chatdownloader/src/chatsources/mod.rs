@@ -0,0 +1,8 @@
+pub mod archive;
+pub mod chatsourcetrait;
+pub mod checkpoint;
+pub mod commentstream;
+pub mod gql;
+pub mod irc;
+pub mod localfile;
+pub mod twitchdownloaderproxy;
@@ -0,0 +1,362 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::{Builder, NamedTempFile, TempPath};
+use zip::ZipArchive;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::_constants::USER_AGENT;
+use crate::_types::twitchtypes::ChatLog;
+use crate::chatsources::chatsourcetrait::ChatSource;
+use crate::chatsources::commentstream::CommentStream;
+
+const RELEASES_URL: &str = "https://api.github.com/repos/lay295/TwitchDownloader/releases";
+/// The TwitchDownloader release used unless another one is configured
+pub const PINNED_VERSION: &str = "1.54.9";
+/// SHA-256 of the Linux x64 release zip of PINNED_VERSION. Update it together with PINNED_VERSION,
+/// from the zip downloaded from the release page. Until it is set, a checksum has to be passed to
+/// download chat with TwitchDownloader
+pub const PINNED_SHA256: &str = "";
+const EXECUTABLE_NAME: &str = "TwitchDownloaderCLI";
+const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct GithubAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct GithubRelease {
+    tag_name: String,
+    assets: Vec<GithubAsset>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheManifest {
+    /*
+    Written next to the cached executable, so a later run can tell which
+    release it came from and whether the file was modified since
+    */
+    version: String,
+    zip_sha256: String,
+    executable_sha256: String,
+}
+
+pub struct TwitchChatDownloader {
+    /*
+    Runs TwitchDownloaderCLI to download chat logs.
+
+    The executable is extracted once into cache_dir/<version>/ and reused
+    by later runs for as long as its checksum matches the one recorded
+    when it was extracted
+    */
+    version: String,
+    /// None if no checksum is known, in which case the executable is not run
+    zip_sha256: Option<String>,
+    cache_dir: PathBuf,
+    executable_path: Option<PathBuf>,
+}
+
+impl TwitchChatDownloader {
+    /// version is a release tag or "latest". The downloaded release zip must match zip_sha256,
+    /// which defaults to PINNED_SHA256 for PINNED_VERSION. Without a checksum to verify against,
+    /// downloading chat fails, as the executable is never run unverified. Chat logs read from
+    /// an archive never need it
+    pub fn new(version: String, zip_sha256: Option<String>, cache_dir: PathBuf) -> Result<Self, String> {
+        let zip_sha256 = match zip_sha256 {
+            Some(sha) if is_sha256(&sha.to_lowercase()) => Some(sha.to_lowercase()),
+            Some(sha) => return Err(format!("{:?} is not a valid SHA-256", sha)),
+            None if version == PINNED_VERSION && is_sha256(PINNED_SHA256) => Some(PINNED_SHA256.to_string()),
+            None => None,
+        };
+        Ok(TwitchChatDownloader {
+            version,
+            zip_sha256,
+            cache_dir,
+            executable_path: None,
+        })
+    }
+
+    /// The default cache directory, e.g. ~/.cache/neuro-chat-elo/twitchdownloader
+    pub fn default_cache_dir() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("neuro-chat-elo")
+            .join("twitchdownloader")
+    }
+
+    async fn get_release(
+        &self,
+        client: &reqwest::Client,
+    ) -> Result<GithubRelease, Box<dyn std::error::Error>> {
+        let url = if self.version == "latest" {
+            format!("{}/latest", RELEASES_URL)
+        } else {
+            format!("{}/tags/{}", RELEASES_URL, self.version)
+        };
+        info!("Fetching release {} from GitHub", self.version);
+
+        let response = client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(Box::new(io::Error::other(format!(
+                "Failed to fetch release {}: {}",
+                self.version,
+                response.status()
+            ))));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Return the cached executable for a release if it is intact and matches the pinned hash
+    fn get_cached_executable(&self, version: &str) -> Option<PathBuf> {
+        let version_dir = self.cache_dir.join(version);
+        let executable_path = version_dir.join(EXECUTABLE_NAME);
+        let manifest: CacheManifest = fs::read_to_string(version_dir.join(MANIFEST_NAME))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())?;
+
+        if manifest.version != version {
+            return None;
+        }
+        if Some(&manifest.zip_sha256) != self.zip_sha256.as_ref() {
+            warn!("Cached {} was extracted from a different zip, downloading again", version);
+            return None;
+        }
+        match sha256_file(&executable_path) {
+            Ok(sha) if sha == manifest.executable_sha256 => Some(executable_path),
+            _ => {
+                warn!("Cached {} executable is missing or was modified, downloading again", version);
+                None
+            }
+        }
+    }
+
+    async fn download_executable(&mut self) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        /*
+        Downloads the configured release into the cache, unless it is already there
+        */
+        let Some(expected_sha256) = self.zip_sha256.clone() else {
+            return Err(format!(
+                "No valid SHA-256 is pinned for TwitchDownloader {}, pass --twitchdownloader-sha256",
+                self.version
+            )
+            .into());
+        };
+        if self.version != "latest" {
+            if let Some(path) = self.get_cached_executable(&self.version) {
+                info!("Using cached TwitchDownloaderCLI {}", self.version);
+                return Ok(path);
+            }
+        }
+
+        let client = reqwest::ClientBuilder::new()
+            .user_agent(USER_AGENT)
+            .build()
+            .expect("Failed to create HTTP Client");
+
+        let release = self.get_release(&client).await?;
+        if let Some(path) = self.get_cached_executable(&release.tag_name) {
+            info!("Using cached TwitchDownloaderCLI {}", release.tag_name);
+            return Ok(path);
+        }
+
+        let asset = release
+            .assets
+            .iter()
+            .find(|a| a.name.ends_with("Linux-x64.zip"))
+            .ok_or("No suitable asset found")?;
+
+        info!("Downloading {}", asset.name);
+        let download_response = client
+            .get(&asset.browser_download_url)
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut temp_zip = NamedTempFile::new()?;
+        io::copy(
+            &mut download_response.bytes().await?.as_ref(),
+            &mut temp_zip,
+        )?;
+
+        let zip_sha256 = sha256_reader(temp_zip.reopen()?)?;
+        if zip_sha256 != expected_sha256 {
+            return Err(Box::new(io::Error::other(format!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                asset.name, expected_sha256, zip_sha256
+            ))));
+        }
+        info!("Verified checksum of {}", asset.name);
+
+        let version_dir = self.cache_dir.join(&release.tag_name);
+        fs::create_dir_all(&version_dir)?;
+        let executable_path = version_dir.join(EXECUTABLE_NAME);
+        extract_executable(temp_zip.reopen()?, &executable_path)?;
+
+        let manifest = CacheManifest {
+            version: release.tag_name.clone(),
+            zip_sha256,
+            executable_sha256: sha256_file(&executable_path)?,
+        };
+        fs::write(
+            version_dir.join(MANIFEST_NAME),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+
+        info!(
+            "Cached TwitchDownloaderCLI {} at {}",
+            release.tag_name,
+            executable_path.display()
+        );
+        Ok(executable_path)
+    }
+
+    /// Run TwitchDownloaderCLI for a VOD. The chat JSON is deleted when the returned path is dropped
+    async fn download_to_file(&mut self, vod_id: &str) -> Result<TempPath, Box<dyn std::error::Error>> {
+        let executable_path = match &self.executable_path {
+            Some(path) => path.clone(),
+            None => {
+                let path = self.download_executable().await?;
+                self.executable_path = Some(path.clone());
+                path
+            }
+        };
+
+        let output_file = Builder::new().suffix(".json").tempfile()?.into_temp_path();
+        let output_path: PathBuf = output_file.to_path_buf();
+        output_file.close()?;
+
+        let status = Command::new(&executable_path)
+            .args(["chatdownload", "-u", vod_id, "-o"])
+            .arg(&output_path)
+            .status()?;
+
+        if !status.success() {
+            return Err(Box::new(io::Error::other("Failed to download chat")));
+        }
+        Ok(TempPath::try_from_path(output_path)?)
+    }
+}
+
+/// Extract TwitchDownloaderCLI from a release zip to destination. The file is
+/// written next to destination first and renamed into place, so an interrupted
+/// extraction never leaves a truncated executable behind
+pub fn extract_executable<R: Read + Seek>(
+    zip_file: R,
+    destination: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut zip = ZipArchive::new(zip_file)?;
+    let mut file = zip.by_name(EXECUTABLE_NAME)?;
+
+    let parent = destination.parent().unwrap_or(Path::new("."));
+    let mut exe_file = NamedTempFile::new_in(parent)?;
+    io::copy(&mut file, &mut exe_file)?;
+    exe_file.as_file().sync_all()?;
+    fs::set_permissions(exe_file.path(), fs::Permissions::from_mode(0o700))?;
+    exe_file.persist(destination)?;
+    Ok(())
+}
+
+/// Whether s is a hex encoded SHA-256, in lower case
+fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    sha256_reader(File::open(path)?)
+}
+
+fn sha256_reader<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+impl ChatSource for TwitchChatDownloader {
+    fn get_name(&self) -> String {
+        "twitch-downloader".to_string()
+    }
+
+    async fn get_chat_log(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<ChatLog, Box<dyn std::error::Error>> {
+        let output_path = self.download_to_file(vod_id).await?;
+
+        let mut file = File::open(&output_path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        info!("Parsing JSON");
+        let chat_log: ChatLog = serde_json::from_str(&contents)?;
+        Ok(chat_log)
+    }
+
+    async fn get_comment_stream(
+        &mut self,
+        vod_id: &str,
+    ) -> Result<CommentStream, Box<dyn std::error::Error>> {
+        let output_path = self.download_to_file(vod_id).await?;
+        info!("Streaming chat log for {}", vod_id);
+        Ok(CommentStream::from_file(output_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const SHA: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn the_pinned_version_has_a_checksum() {
+        assert!(is_sha256(PINNED_SHA256), "PINNED_SHA256 must be set for {}", PINNED_VERSION);
+        let pinned = TwitchChatDownloader::new(PINNED_VERSION.into(), None, PathBuf::new()).unwrap();
+        assert_eq!(pinned.zip_sha256.as_deref(), Some(PINNED_SHA256));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_run_without_a_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        for version in ["1.0.0", "latest"] {
+            let mut unpinned = TwitchChatDownloader::new(version.into(), None, dir.path().into()).unwrap();
+            let error = unpinned.download_executable().await.unwrap_err();
+            assert!(error.to_string().contains("--twitchdownloader-sha256"));
+        }
+        let malformed =
+            TwitchChatDownloader::new("1.0.0".into(), Some("abc".into()), dir.path().into());
+        assert!(malformed.is_err());
+        let given = TwitchChatDownloader::new("1.0.0".into(), Some(SHA.to_uppercase()), dir.path().into());
+        assert_eq!(given.unwrap().zip_sha256.as_deref(), Some(SHA));
+    }
+
+    #[test]
+    fn only_uses_a_cached_executable_from_the_expected_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let version_dir = dir.path().join("1.0.0");
+        fs::create_dir_all(&version_dir).unwrap();
+        let executable_path = version_dir.join(EXECUTABLE_NAME);
+        File::create(&executable_path).unwrap().write_all(b"#!/bin/sh\n").unwrap();
+        let manifest = CacheManifest {
+            version: "1.0.0".into(),
+            zip_sha256: SHA.into(),
+            executable_sha256: sha256_file(&executable_path).unwrap(),
+        };
+        fs::write(version_dir.join(MANIFEST_NAME), serde_json::to_string(&manifest).unwrap()).unwrap();
+
+        let expected = TwitchChatDownloader::new("1.0.0".into(), Some(SHA.into()), dir.path().into()).unwrap();
+        assert_eq!(expected.get_cached_executable("1.0.0"), Some(executable_path.clone()));
+        let other_zip = "0".repeat(64);
+        let other = TwitchChatDownloader::new("1.0.0".into(), Some(other_zip), dir.path().into()).unwrap();
+        assert_eq!(other.get_cached_executable("1.0.0"), None);
+
+        // A modified executable is not used either
+        File::create(&executable_path).unwrap().write_all(b"tampered").unwrap();
+        assert_eq!(expected.get_cached_executable("1.0.0"), None);
+    }
+}
@@ -0,0 +1,278 @@
+/*
+Command line interface for the chatdownloader binary
+*/
+
+use std::fs;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::_constants::VED_CH_ID;
+use crate::_types::clptypes::Channel;
+use crate::chatsources::gql::GQL_URL;
+use crate::chatsources::irc::TWITCH_IRC_ADDR;
+use crate::chatsources::twitchdownloaderproxy::PINNED_VERSION;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Computes the Neuro chat ELO leaderboards from Twitch VOD chat logs")]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct GlobalArgs {
+    /// Twitch channel IDs whose chat is scored, comma separated. The first channel's
+    /// leaderboards are kept in <output-dir>, those of any other in <output-dir>/<channel-id>/
+    #[arg(
+        long = "channel-id",
+        global = true,
+        env = "CHANNEL_ID",
+        value_delimiter = ',',
+        default_value = VED_CH_ID
+    )]
+    pub channel_ids: Vec<String>,
+
+    /// TOML or JSON file with the scoring weights. Values it leaves out keep their defaults
+    #[arg(long, global = true, env = "SCORING_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Directory the leaderboard files are read from and written to
+    #[arg(long, global = true, env = "OUTPUT_DIR", default_value = ".")]
+    pub output_dir: PathBuf,
+
+    /// Season whose leaderboards are read and written, kept in <output-dir>/seasons/<season>/.
+    /// Defaults to the season in --season-file. Without either, the leaderboards are kept in
+    /// <output-dir> directly
+    #[arg(long, global = true, env = "SEASON")]
+    pub season: Option<String>,
+
+    /// File holding the current season, e.g. the repository's season.txt. close-season writes
+    /// the next season to it if it exists
+    #[arg(long, global = true, env = "SEASON_FILE", default_value = "../season.txt")]
+    pub season_file: PathBuf,
+
+    /// SQLite database that every processed stream, the performances in it and the resulting
+    /// leaderboards are also recorded in. Created if it does not exist
+    #[cfg(feature = "sqlite")]
+    #[arg(long, global = true, env = "DATABASE")]
+    pub database: Option<PathBuf>,
+
+    /// Log filter passed to env_logger (e.g. "info", "debug", "chatdownloader=trace")
+    #[arg(long, global = true, env = "MY_LOG_LEVEL", default_value = "info")]
+    pub log_level: String,
+
+    /// Where chat logs are obtained from
+    #[arg(long, global = true, env = "CHAT_SOURCE", value_enum, default_value_t = ChatSourceKind::TwitchDownloader)]
+    pub source: ChatSourceKind,
+
+    /// Chat JSON file or directory of `<vod_id>.json` files, used by the local source
+    #[arg(long, global = true, env = "CHAT_DIR", required_if_eq("source", "local"))]
+    pub chat_dir: Option<PathBuf>,
+
+    /// GraphQL endpoint used by the gql source, e.g. a local mock server
+    #[arg(long, global = true, env = "GQL_ENDPOINT", default_value = GQL_URL)]
+    pub gql_endpoint: String,
+
+    /// Directory where the chat log of every processed VOD is archived, compressed, and read
+    /// from when the VOD is processed again
+    #[arg(long, global = true, env = "ARCHIVE_DIR")]
+    pub archive_dir: Option<PathBuf>,
+
+    /// Directory where the gql source checkpoints partial downloads so they can be resumed
+    #[arg(long, global = true, env = "CHECKPOINT_DIR")]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// TwitchDownloader release tag used by the twitch-downloader source, or "latest"
+    #[arg(long, global = true, env = "TWITCHDOWNLOADER_VERSION", default_value = PINNED_VERSION)]
+    pub twitchdownloader_version: String,
+
+    /// Expected SHA-256 of the TwitchDownloader release zip, needed for any release but the pinned
+    /// one. The twitch-downloader source refuses to download chat without it or if the download does not match
+    #[arg(long, global = true, env = "TWITCHDOWNLOADER_SHA256")]
+    pub twitchdownloader_sha256: Option<String>,
+
+    /// Where the extracted TwitchDownloaderCLI is cached between runs
+    #[arg(long, global = true, env = "TWITCHDOWNLOADER_CACHE_DIR")]
+    pub twitchdownloader_cache_dir: Option<PathBuf>,
+}
+
+impl GlobalArgs {
+    /// Read the season from --season-file when --season was not given
+    pub fn resolve_season(&mut self) -> Result<(), String> {
+        if self.season.is_none() && self.season_file.exists() {
+            let season = fs::read_to_string(&self.season_file).map_err(|e| {
+                format!("Unable to read the season from {}: {}", self.season_file.display(), e)
+            })?;
+            self.season = Some(season.trim().to_string());
+        }
+        match &self.season {
+            Some(season) => validate_season(season),
+            None => Ok(()),
+        }
+    }
+
+    /// Every configured channel, along with the directory of its leaderboards. The first
+    /// channel stays in the output directory, so adding a channel leaves its data in place
+    pub fn channels(&self) -> Vec<Channel> {
+        self.channel_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| Channel {
+                id: id.clone(),
+                output_dir: if i == 0 {
+                    self.output_dir.clone()
+                } else {
+                    self.output_dir.join(id)
+                },
+                season: self.season.clone(),
+                #[cfg(feature = "sqlite")]
+                database: self.database.clone(),
+            })
+            .collect()
+    }
+
+    /// The channel for commands that score the chat of one channel only
+    pub fn single_channel(&self) -> Result<Channel, String> {
+        match self.channels().as_slice() {
+            [channel] => Ok(channel.clone()),
+            _ => Err(format!(
+                "This command works on a single channel, but {} were given",
+                self.channel_ids.len()
+            )),
+        }
+    }
+}
+
+/// Seasons name a directory, so they must be a single path component
+pub fn validate_season(season: &str) -> Result<(), String> {
+    let valid = !season.is_empty()
+        && season != "."
+        && season != ".."
+        && !season.contains(['/', '\\']);
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("\"{}\" is not a valid season name", season))
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatSourceKind {
+    /// Download with the TwitchDownloaderCLI release binary
+    TwitchDownloader,
+    /// Read already downloaded chat logs from --chat-dir
+    Local,
+    /// Download comments natively from Twitch's GraphQL API
+    Gql,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Process the latest VOD of every channel
+    RunLatest,
+
+    /// Download and process the given VODs, in the order given
+    Process {
+        /// Twitch VOD IDs to process
+        #[arg(required = true)]
+        vod_ids: Vec<String>,
+    },
+
+    /// Process an already downloaded TwitchDownloader chat JSON file
+    ProcessFile {
+        /// Path to the chat JSON produced by TwitchDownloaderCLI
+        chat_file: PathBuf,
+
+        /// Do not contact Twitch or 7TV. No credentials are needed. Badges and 7TV emotes
+        /// are those an online run saved in --archive-dir under the file's name, if any
+        #[arg(long)]
+        offline: bool,
+    },
+
+    /// Score the channel's chat live over IRC, exporting the leaderboards on Ctrl-C or
+    /// when the connection cannot be restored
+    Live {
+        /// Login name of the channel whose chat is joined. Looked up from the channel ID when
+        /// not given, and must belong to it unless --offline
+        #[arg(long, env = "CHANNEL_LOGIN", required_if_eq("offline", "true"))]
+        channel_login: Option<String>,
+
+        /// IRC server to connect to, e.g. a local server replaying a recorded session
+        #[arg(long, env = "IRC_ADDR", default_value = TWITCH_IRC_ADDR)]
+        irc_addr: String,
+
+        /// Do not contact the Twitch API or 7TV. Badges and 7TV emotes are not resolved
+        #[arg(long)]
+        offline: bool,
+    },
+
+    /// Wait for EventSub stream.offline webhooks and process the VOD of every stream that ends,
+    /// for each of the channels
+    Daemon {
+        /// Address the webhook server listens on
+        #[arg(long, env = "LISTEN_ADDR", default_value = "0.0.0.0:8080")]
+        listen_addr: String,
+
+        /// Secret shared with Twitch, used to verify webhook signatures (10 to 100 characters)
+        #[arg(long, env = "EVENTSUB_SECRET")]
+        eventsub_secret: String,
+
+        /// Public HTTPS URL Twitch delivers webhooks to. When given, a stream.offline
+        /// subscription is created for each channel on startup
+        #[arg(long, env = "EVENTSUB_CALLBACK_URL")]
+        callback_url: Option<String>,
+
+        /// Seconds to wait after the stream ends before fetching the VOD, so Twitch has finished it
+        #[arg(long, env = "VOD_DELAY_SECS", default_value_t = 120)]
+        vod_delay_secs: u64,
+    },
+
+    /// Download the chat logs of the given VODs into the output directory without processing them
+    Download {
+        /// Twitch VOD IDs to download
+        #[arg(required = true)]
+        vod_ids: Vec<String>,
+    },
+
+    /// Process every VOD listed in a file, one ID per line
+    Backfill {
+        /// File with one VOD ID per line, processed from top to bottom
+        #[arg(long)]
+        from_file: PathBuf,
+    },
+
+    /// Discard the existing leaderboards of the current season and rebuild them from a list of
+    /// VODs, or from every VOD in the chat archive. A single rollback restores the discarded ones
+    Recompute {
+        /// File with one VOD ID per line, processed from top to bottom. Without it, every VOD in
+        /// --archive-dir is processed in the order they were broadcast
+        #[arg(long)]
+        from_file: Option<PathBuf>,
+
+        /// Do not contact Twitch or 7TV. Badges and 7TV emotes are those saved in
+        /// --archive-dir when each VOD was processed online, if any
+        #[arg(long)]
+        offline: bool,
+
+        /// Recompute twice and fail unless both runs produce byte-identical leaderboards,
+        /// histories and reports
+        #[arg(long)]
+        verify: bool,
+    },
+
+    /// Close the current season of every channel and start the next one, where everyone
+    /// starts again from the initial ELO. The all-time leaderboards are updated first
+    CloseSeason {
+        /// Name of the season that starts, e.g. 2.2
+        next: String,
+    },
+
+    /// Undo the last leaderboard update of every channel, restoring the leaderboards it
+    /// replaced from their backup. A recompute counts as one update. Running it again goes
+    /// back one more update
+    Rollback,
+}
@@ -0,0 +1,390 @@
+/*
+Scoring configuration, loaded at startup from a TOML or JSON file.
+
+Every value defaults to the one the leaderboards have always used, so a
+config file only needs the values it changes
+*/
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::_types::clptypes::{MergeRule, UserChatPerformance};
+use crate::metadata::METADATA_NAMES;
+use crate::metrics::METRIC_NAMES;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub metrics: MetricsConfig,
+    pub metadata: MetadataConfig,
+    pub leaderboards: LeaderboardsConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Names of metrics that are not calculated, e.g. ["emote"]
+    pub disabled: Vec<String>,
+    pub bits: WeightConfig,
+    pub subs: WeightConfig,
+    pub text: WeightConfig,
+    pub emote: WeightConfig,
+    pub copypasta: CopypastaConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataConfig {
+    /// Names of metadata that is not collected, e.g. ["badges"]
+    pub disabled: Vec<String>,
+    /// How repeated updates are combined, replacing the rule the metadata declares,
+    /// e.g. special_role = "last_wins"
+    pub merge: BTreeMap<String, MergeRule>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WeightConfig {
+    /// Score given per bit, gifted sub, character or emote
+    pub weight: f32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CopypastaConfig {
+    /// Score given to whoever started a copypasta chain for each other user who carried it on
+    pub weight: f32,
+    /// How many messages a chain may go without being repeated before it ends
+    pub chain_grace: u32,
+    /// How many seconds a chain may go without being repeated before it ends. When set,
+    /// this replaces chain_grace, so the window does not depend on how fast chat is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_grace_seconds: Option<f32>,
+    /// How similar two messages must be, from 0 to 1, to belong to the same chain. This is the
+    /// share of character trigrams they have in common, see metrics::similarity
+    pub matching_threshold: f32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderboardsConfig {
+    /// The ELO K-factor, i.e. the most a user's ELO can move per sampled opponent
+    pub elo_k: f32,
+    /// The leaderboards to keep. Giving any replaces the built-in ones
+    pub boards: Vec<LeaderboardDefinition>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LeaderboardDefinition {
+    /*
+    A leaderboard, saved as <name>.json. A user's score on it is the
+    weighted sum of their metrics, and only users matching every
+    eligibility rule are ranked
+    */
+    pub name: String,
+    /// Weight of each metric by name
+    #[serde(default)]
+    pub weights: BTreeMap<String, f32>,
+    /// Weight of the metrics not listed in weights
+    #[serde(default)]
+    pub default_weight: f32,
+    /// Boolean metadata a user must have, e.g. special_role = false. Missing metadata counts as false
+    #[serde(default)]
+    pub eligibility: BTreeMap<String, bool>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            disabled: Vec::new(),
+            bits: WeightConfig { weight: 0.1 },
+            subs: WeightConfig { weight: 0.1 },
+            text: WeightConfig { weight: 0.02 },
+            emote: WeightConfig { weight: 0.02 },
+            copypasta: CopypastaConfig::default(),
+        }
+    }
+}
+
+impl Default for CopypastaConfig {
+    fn default() -> Self {
+        Self {
+            weight: 0.3,
+            chain_grace: 10,
+            chain_grace_seconds: None,
+            matching_threshold: 0.6,
+        }
+    }
+}
+
+impl Default for LeaderboardsConfig {
+    fn default() -> Self {
+        let single_metric = |name: &str, metric: &str, weight: f32| LeaderboardDefinition {
+            name: name.to_string(),
+            weights: BTreeMap::from([(metric.to_string(), weight)]),
+            default_weight: 0.0,
+            eligibility: BTreeMap::new(),
+        };
+        let all_metrics = |name: &str, eligibility: BTreeMap<String, bool>| LeaderboardDefinition {
+            name: name.to_string(),
+            weights: BTreeMap::new(),
+            default_weight: 1.0,
+            eligibility,
+        };
+
+        Self {
+            elo_k: 2.0,
+            boards: vec![
+                single_metric("bits-only", "bits", 2.0),
+                single_metric("chat-only", "text", 1.0),
+                single_metric("copypasta", "copypasta", 1.0),
+                all_metrics("nonvips", BTreeMap::from([("special_role".to_string(), false)])),
+                all_metrics("overall", BTreeMap::new()),
+                single_metric("subs-only", "subs", 1.0),
+            ],
+        }
+    }
+}
+
+impl LeaderboardDefinition {
+    /// The user's score, or None if they are not eligible for this leaderboard
+    pub fn score(&self, performance: &UserChatPerformance) -> Option<f32> {
+        let eligible = self.eligibility.iter().all(|(name, expected)| {
+            let value = performance
+                .metadata
+                .get(name)
+                .and_then(|value| value.get_bool())
+                .copied()
+                .unwrap_or(false);
+            value == *expected
+        });
+        if !eligible {
+            return None;
+        }
+
+        // Sum in order of name, as the order of a float sum changes its rounding
+        let mut metrics: Vec<(&String, &f32)> = performance.metrics.iter().collect();
+        metrics.sort_by_key(|(name, _)| *name);
+        Some(
+            metrics
+                .into_iter()
+                .map(|(name, value)| value * self.weights.get(name).unwrap_or(&self.default_weight))
+                .sum(),
+        )
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConfigRecord<'a> {
+    /*
+    Written next to each leaderboard, so the file states which
+    configuration produced it
+    */
+    pub config_sha256: String,
+    pub config: &'a Config,
+}
+
+impl Config {
+    /// Load and validate a config file. Files ending in .json are read as JSON, anything else as TOML
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read config {}: {}", path.display(), e))?;
+        let config: Config = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?
+        } else {
+            toml::from_str(&contents)
+                .map_err(|e| format!("Invalid config {}: {}", path.display(), e))?
+        };
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(format!(
+                "Invalid config {}:\n  {}",
+                path.display(),
+                problems.join("\n  ")
+            )
+            .into());
+        }
+        info!("Loaded config {} ({})", path.display(), config.sha256());
+        Ok(config)
+    }
+
+    /// Every problem with the values, e.g. negative weights, as readable messages
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let weights = [
+            ("metrics.bits.weight", self.metrics.bits.weight),
+            ("metrics.subs.weight", self.metrics.subs.weight),
+            ("metrics.text.weight", self.metrics.text.weight),
+            ("metrics.emote.weight", self.metrics.emote.weight),
+            ("metrics.copypasta.weight", self.metrics.copypasta.weight),
+        ];
+        for (name, value) in weights {
+            if !value.is_finite() || value < 0.0 {
+                problems.push(format!("{} must be a number of at least 0, got {}", name, value));
+            }
+        }
+
+        for name in self.metrics.disabled.iter() {
+            if !METRIC_NAMES.contains(&name.as_str()) {
+                problems.push(format!(
+                    "metrics.disabled: unknown metric {:?}, expected one of {}",
+                    name,
+                    METRIC_NAMES.join(", ")
+                ));
+            }
+        }
+
+        let metadata_names = self.metadata.disabled.iter().chain(self.metadata.merge.keys());
+        for name in metadata_names {
+            if !METADATA_NAMES.contains(&name.as_str()) {
+                problems.push(format!(
+                    "metadata: unknown metadata {:?}, expected one of {}",
+                    name,
+                    METADATA_NAMES.join(", ")
+                ));
+            }
+        }
+
+        let threshold = self.metrics.copypasta.matching_threshold;
+        if !(0.0..=1.0).contains(&threshold) {
+            problems.push(format!(
+                "metrics.copypasta.matching_threshold must be between 0 and 1, got {}",
+                threshold
+            ));
+        }
+        if self.metrics.copypasta.chain_grace == 0 {
+            problems.push("metrics.copypasta.chain_grace must be at least 1".to_string());
+        }
+        if let Some(seconds) = self.metrics.copypasta.chain_grace_seconds {
+            if !seconds.is_finite() || seconds <= 0.0 {
+                problems.push(format!(
+                    "metrics.copypasta.chain_grace_seconds must be a number above 0, got {}",
+                    seconds
+                ));
+            }
+        }
+        problems.extend(self.validate_boards());
+        if !self.leaderboards.elo_k.is_finite() || self.leaderboards.elo_k <= 0.0 {
+            problems.push(format!(
+                "leaderboards.elo_k must be a number above 0, got {}",
+                self.leaderboards.elo_k
+            ));
+        }
+        problems
+    }
+
+    fn validate_boards(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.leaderboards.boards.is_empty() {
+            problems.push("leaderboards.boards must define at least one leaderboard".to_string());
+        }
+
+        let mut names = HashSet::new();
+        for board in self.leaderboards.boards.iter() {
+            let valid_name = !board.name.is_empty()
+                && board
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid_name {
+                problems.push(format!(
+                    "leaderboard name {:?} may only contain letters, digits, - and _",
+                    board.name
+                ));
+            }
+            if !names.insert(board.name.as_str()) {
+                problems.push(format!("leaderboard {} is defined more than once", board.name));
+            }
+
+            for metric in board.weights.keys() {
+                if !METRIC_NAMES.contains(&metric.as_str()) {
+                    problems.push(format!(
+                        "leaderboard {}: weights: unknown metric {:?}, expected one of {}",
+                        board.name,
+                        metric,
+                        METRIC_NAMES.join(", ")
+                    ));
+                }
+            }
+            for metadata in board.eligibility.keys() {
+                if !METADATA_NAMES.contains(&metadata.as_str()) {
+                    problems.push(format!(
+                        "leaderboard {}: eligibility: unknown metadata {:?}, expected one of {}",
+                        board.name,
+                        metadata,
+                        METADATA_NAMES.join(", ")
+                    ));
+                }
+            }
+
+            let weights = board
+                .weights
+                .iter()
+                .map(|(metric, weight)| (format!("weights.{}", metric), *weight))
+                .chain([("default_weight".to_string(), board.default_weight)]);
+            for (name, weight) in weights {
+                if !weight.is_finite() {
+                    problems.push(format!(
+                        "leaderboard {}: {} must be a number, got {}",
+                        board.name, name, weight
+                    ));
+                }
+            }
+            if board.weights.is_empty() && board.default_weight == 0.0 {
+                problems.push(format!(
+                    "leaderboard {} gives every metric a weight of 0, set weights or default_weight",
+                    board.name
+                ));
+            }
+        }
+        problems
+    }
+
+    /// SHA-256 of the config serialized as JSON, identifying it independently of how the file was formatted
+    pub fn sha256(&self) -> String {
+        let json = serde_json::to_vec(self).expect("Unable to serialize config");
+        hex::encode(Sha256::digest(json))
+    }
+
+    pub fn record(&self) -> ConfigRecord<'_> {
+        ConfigRecord {
+            config_sha256: self.sha256(),
+            config: self,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_default_config_is_valid() {
+        assert_eq!(Config::default().validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_unknown_weight_and_eligibility_names() {
+        let mut config = Config::default();
+        config.leaderboards.boards.push(LeaderboardDefinition {
+            name: "typos".to_string(),
+            weights: BTreeMap::from([("bits".to_string(), 1.0), ("emotes".to_string(), 1.0)]),
+            default_weight: 0.0,
+            eligibility: BTreeMap::from([("special_roles".to_string(), false)]),
+        });
+        assert_eq!(
+            config.validate(),
+            [
+                "leaderboard typos: weights: unknown metric \"emotes\", expected one of bits, subs, text, copypasta, emote",
+                "leaderboard typos: eligibility: unknown metadata \"special_roles\", expected one of basic_info, badges, special_role",
+            ]
+        );
+    }
+}
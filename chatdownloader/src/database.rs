@@ -0,0 +1,181 @@
+/*
+SQLite store of every processed stream, built with the sqlite feature.
+
+The leaderboards are still computed from, and exported to, their JSON
+files for the static site. The database additionally keeps what the
+files cannot: the users, every stream, each user's performance in each
+stream and a snapshot of every leaderboard after each stream, so they
+can be queried, e.g. the top chatters in March:
+
+    SELECT users.username, SUM(performance_metrics.value) AS text
+    FROM performance_metrics
+    JOIN streams USING (channel_id, stream_id)
+    JOIN users ON users.id = performance_metrics.user_id
+    WHERE performance_metrics.metric = 'text'
+      AND streams.started_at >= '2024-03-01' AND streams.started_at < '2024-04-01'
+    GROUP BY performance_metrics.user_id
+    ORDER BY text DESC
+    LIMIT 10;
+
+Processing a stream again, e.g. when recomputing, replaces its rows.
+A stream is recorded only after its leaderboards are committed, so the
+database never holds a stream the files do not. It is not part of their
+transaction though: rolling the files back leaves the stream's rows, and
+if recording fails the files are already updated. Recomputing brings the
+database back in line with the files
+*/
+
+use chrono::Utc;
+use log::info;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+use crate::_types::clptypes::{Channel, StreamInfo, UserChatPerformance};
+use crate::_types::leaderboardtypes::LeaderboardSnapshot;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    avatar TEXT NOT NULL,
+    last_seen TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS streams (
+    channel_id TEXT NOT NULL,
+    stream_id TEXT NOT NULL,
+    season TEXT,
+    started_at TEXT NOT NULL,
+    processed_at TEXT NOT NULL,
+    PRIMARY KEY (channel_id, stream_id)
+);
+CREATE TABLE IF NOT EXISTS performances (
+    channel_id TEXT NOT NULL,
+    stream_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    username TEXT NOT NULL,
+    metadata TEXT NOT NULL,
+    PRIMARY KEY (channel_id, stream_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS performance_metrics (
+    channel_id TEXT NOT NULL,
+    stream_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    value REAL NOT NULL,
+    PRIMARY KEY (channel_id, stream_id, user_id, metric)
+);
+CREATE TABLE IF NOT EXISTS leaderboard_snapshots (
+    channel_id TEXT NOT NULL,
+    stream_id TEXT NOT NULL,
+    leaderboard TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    rank INTEGER NOT NULL,
+    elo REAL NOT NULL,
+    delta INTEGER NOT NULL,
+    PRIMARY KEY (channel_id, stream_id, leaderboard, user_id)
+);
+CREATE INDEX IF NOT EXISTS streams_started_at ON streams (started_at);
+CREATE INDEX IF NOT EXISTS leaderboard_snapshots_user ON leaderboard_snapshots (leaderboard, user_id);
+";
+
+/// The tables with rows for each stream, cleared when a stream is recorded again
+const STREAM_TABLES: [&str; 4] = [
+    "streams",
+    "performances",
+    "performance_metrics",
+    "leaderboard_snapshots",
+];
+
+pub struct Database {
+    /*
+    An open database, with its tables created if it is new
+    */
+    connection: Connection,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    /// Record a stream of the channel, the performances in it and the leaderboards it produced,
+    /// all in one transaction
+    pub fn record_stream(
+        &mut self,
+        channel: &Channel,
+        stream: &StreamInfo,
+        performances: &[UserChatPerformance],
+        snapshots: &[LeaderboardSnapshot],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let transaction = self.connection.transaction()?;
+        for table in STREAM_TABLES {
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE channel_id = ?1 AND stream_id = ?2", table),
+                params![channel.id, stream.id],
+            )?;
+        }
+        transaction.execute(
+            "INSERT INTO streams (channel_id, stream_id, season, started_at, processed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![channel.id, stream.id, channel.season, stream.started_at, Utc::now()],
+        )?;
+
+        {
+            let mut user = transaction.prepare(
+                "INSERT INTO users (id, username, avatar, last_seen) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (id) DO UPDATE SET username = excluded.username, avatar = excluded.avatar,
+                     last_seen = excluded.last_seen
+                 WHERE excluded.last_seen >= users.last_seen",
+            )?;
+            let mut performance = transaction.prepare(
+                "INSERT INTO performances (channel_id, stream_id, user_id, username, metadata)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let mut metric = transaction.prepare(
+                "INSERT INTO performance_metrics (channel_id, stream_id, user_id, metric, value)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for item in performances {
+                user.execute(params![item.id, item.username, item.avatar, stream.started_at])?;
+                performance.execute(params![
+                    channel.id,
+                    stream.id,
+                    item.id,
+                    item.username,
+                    serde_json::to_string(&item.metadata)?
+                ])?;
+                for (name, value) in item.metrics.iter() {
+                    metric.execute(params![channel.id, stream.id, item.id, name, value])?;
+                }
+            }
+
+            let mut snapshot = transaction.prepare(
+                "INSERT INTO leaderboard_snapshots (channel_id, stream_id, leaderboard, user_id, rank, elo, delta)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for board in snapshots {
+                for item in board.items.iter() {
+                    snapshot.execute(params![
+                        channel.id,
+                        stream.id,
+                        board.name,
+                        item.id,
+                        item.rank,
+                        item.elo,
+                        item.delta
+                    ])?;
+                }
+            }
+        }
+        transaction.commit()?;
+        info!(
+            "Recorded stream {} of channel {} with {} performances in the database",
+            stream.id,
+            channel.id,
+            performances.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+/*
+Receives EventSub webhooks from Twitch and reports when a stream goes offline
+*/
+
+use axum::body::{to_bytes, Body};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use twitch_api::eventsub::{Event, Message, Payload};
+
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Twitch recommends rejecting notifications older than 10 minutes
+const MAX_MESSAGE_AGE_MINUTES: i64 = 10;
+
+struct WebhookState {
+    secret: String,
+    channel_ids: HashSet<String>,
+    /// When each handled message was sent. Stale messages are rejected anyway, so
+    /// message IDs are forgotten once they are older than MAX_MESSAGE_AGE_MINUTES
+    seen_message_ids: Mutex<HashMap<String, DateTime<Utc>>>,
+    offline_sender: mpsc::Sender<String>,
+}
+
+impl WebhookState {
+    fn new(
+        secret: String,
+        channel_ids: HashSet<String>,
+        offline_sender: mpsc::Sender<String>,
+    ) -> Self {
+        Self {
+            secret,
+            channel_ids,
+            seen_message_ids: Mutex::new(HashMap::new()),
+            offline_sender,
+        }
+    }
+}
+
+/// Listen for EventSub webhooks on addr. The broadcaster ID is sent on the returned
+/// channel every time a verified stream.offline notification for one of channel_ids arrives
+pub async fn serve(
+    addr: &str,
+    secret: String,
+    channel_ids: HashSet<String>,
+) -> Result<mpsc::Receiver<String>, Box<dyn std::error::Error>> {
+    let (offline_sender, offline_receiver) = mpsc::channel(16);
+    let state = Arc::new(WebhookState::new(secret, channel_ids, offline_sender));
+
+    let app = Router::new()
+        .route("/", post(handle_webhook))
+        .route("/eventsub", post(handle_webhook))
+        .with_state(state);
+
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening for EventSub webhooks on {}", listener.local_addr()?);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("EventSub webhook server stopped: {}", e);
+        }
+    });
+
+    Ok(offline_receiver)
+}
+
+async fn handle_webhook(State(state): State<Arc<WebhookState>>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    let request = axum::http::Request::from_parts(parts, body.to_vec());
+
+    if !Event::verify_payload(&request, state.secret.as_bytes()) {
+        warn!("Rejecting EventSub message with an invalid signature");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let message_id = header("Twitch-Eventsub-Message-Id");
+    let timestamp = header("Twitch-Eventsub-Message-Timestamp");
+
+    let sent_at = match DateTime::parse_from_rfc3339(&timestamp) {
+        Ok(sent_at) if Utc::now() - sent_at.with_timezone(&Utc) <= Duration::minutes(MAX_MESSAGE_AGE_MINUTES) => {
+            sent_at.with_timezone(&Utc)
+        }
+        _ => {
+            warn!("Rejecting stale EventSub message {} sent at {}", message_id, timestamp);
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    };
+
+    if state.seen_message_ids.lock().unwrap().contains_key(&message_id) {
+        debug!("Ignoring redelivered EventSub message {}", message_id);
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    let response = handle_event(&state, &request, &message_id).await;
+    // Twitch redelivers messages that were not handled, so only remember the handled ones
+    if response.status().is_success() {
+        let mut seen = state.seen_message_ids.lock().unwrap();
+        let oldest = Utc::now() - Duration::minutes(MAX_MESSAGE_AGE_MINUTES);
+        seen.retain(|_, seen_sent_at| *seen_sent_at >= oldest);
+        seen.insert(message_id, sent_at);
+    }
+    response
+}
+
+async fn handle_event(
+    state: &WebhookState,
+    request: &axum::http::Request<Vec<u8>>,
+    message_id: &str,
+) -> Response {
+    match Event::parse_http(request) {
+        Ok(Event::StreamOfflineV1(Payload { message, .. })) => match message {
+            Message::VerificationRequest(verification) => {
+                info!("Answering EventSub webhook verification");
+                Response::new(Body::from(verification.challenge))
+            }
+            Message::Notification(notification) => {
+                let broadcaster_id = notification.broadcaster_user_id.to_string();
+                if !state.channel_ids.contains(&broadcaster_id) {
+                    debug!("Ignoring stream.offline for channel {}", broadcaster_id);
+                    return StatusCode::NO_CONTENT.into_response();
+                }
+                info!("{} went offline", notification.broadcaster_user_login);
+                if let Err(e) = state.offline_sender.send(broadcaster_id).await {
+                    warn!("Could not queue stream offline event: {}", e);
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+                StatusCode::NO_CONTENT.into_response()
+            }
+            Message::Revocation() => {
+                warn!("Twitch revoked the stream.offline subscription");
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::NO_CONTENT.into_response(),
+        },
+        Ok(_) => {
+            debug!("Ignoring unrelated EventSub message {}", message_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            warn!("Could not parse EventSub message {}: {}", message_id, e);
+            StatusCode::BAD_REQUEST.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SecondsFormat;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    /// A stream.offline notification for channel 1337, as Twitch sends it
+    const STREAM_OFFLINE: &str = include_str!("../tests/fixtures/eventsub/stream_offline.json");
+    const SECRET: &str = "s3cre7-webhook-secret";
+
+    fn state() -> (Arc<WebhookState>, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(16);
+        let channel_ids = HashSet::from(["1337".to_string()]);
+        (Arc::new(WebhookState::new(SECRET.to_string(), channel_ids, sender)), receiver)
+    }
+
+    /// A notification signed with secret the way Twitch signs it
+    fn signed_request(message_id: &str, sent_at: DateTime<Utc>, secret: &str) -> Request {
+        let timestamp = sent_at.to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message_id.as_bytes());
+        mac.update(timestamp.as_bytes());
+        mac.update(STREAM_OFFLINE.as_bytes());
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+
+        Request::builder()
+            .method("POST")
+            .uri("/eventsub")
+            .header("Content-Type", "application/json")
+            .header("Twitch-Eventsub-Message-Id", message_id)
+            .header("Twitch-Eventsub-Message-Retry", "0")
+            .header("Twitch-Eventsub-Message-Type", "notification")
+            .header("Twitch-Eventsub-Message-Signature", signature)
+            .header("Twitch-Eventsub-Message-Timestamp", timestamp)
+            .header("Twitch-Eventsub-Subscription-Type", "stream.offline")
+            .header("Twitch-Eventsub-Subscription-Version", "1")
+            .body(Body::from(STREAM_OFFLINE))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_a_signed_notification() {
+        let (state, mut receiver) = state();
+        let response = handle_webhook(State(state), signed_request("msg-1", Utc::now(), SECRET)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(receiver.try_recv().unwrap(), "1337");
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_signature() {
+        let (state, mut receiver) = state();
+        let response = handle_webhook(State(state), signed_request("msg-1", Utc::now(), "wrong-secret")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_stale_message() {
+        let (state, mut receiver) = state();
+        let sent_at = Utc::now() - Duration::minutes(MAX_MESSAGE_AGE_MINUTES + 1);
+        let response = handle_webhook(State(state), signed_request("msg-1", sent_at, SECRET)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn ignores_a_redelivered_message() {
+        let (state, mut receiver) = state();
+        for _ in 0..2 {
+            let response = handle_webhook(State(state.clone()), signed_request("msg-1", Utc::now(), SECRET)).await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+        assert_eq!(receiver.try_recv().unwrap(), "1337");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn does_not_remember_a_message_that_was_not_queued() {
+        let (state, receiver) = state();
+        drop(receiver);
+        let response = handle_webhook(State(state.clone()), signed_request("msg-1", Utc::now(), SECRET)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(state.seen_message_ids.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forgets_message_ids_once_they_are_stale() {
+        let (state, _receiver) = state();
+        let long_ago = Utc::now() - Duration::minutes(MAX_MESSAGE_AGE_MINUTES + 1);
+        state.seen_message_ids.lock().unwrap().insert("msg-old".to_string(), long_ago);
+
+        let response = handle_webhook(State(state.clone()), signed_request("msg-1", Utc::now(), SECRET)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let seen = state.seen_message_ids.lock().unwrap();
+        assert_eq!(seen.keys().collect::<Vec<_>>(), ["msg-1"]);
+    }
+}
@@ -0,0 +1,91 @@
+/*
+The all-time leaderboards, which combine every season of a channel.
+
+A user's all-time ELO is the initial ELO plus what they gained or lost
+in each season they took part in, so a long history counts as well as
+a single strong season. Users keep the name, avatar and badges of the
+latest season they were in. The all-time leaderboards have the same
+format as a season's and are kept in <output dir>/all-time/
+*/
+
+use log::info;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::_types::clptypes::Channel;
+use crate::_types::leaderboardtypes::LeaderboardExportItem;
+use crate::config::Config;
+use crate::leaderboards::leaderboard::Leaderboard;
+use crate::leaderboards::leaderboardtrait::{AbstractLeaderboard, INITIAL_ELO};
+use crate::leaderboards::transaction::Transaction;
+use crate::seasons::{list_seasons, season_dir};
+
+const ALL_TIME_DIR: &str = "all-time";
+
+/// The directory of the channel's all-time leaderboards
+pub fn all_time_dir(channel: &Channel) -> PathBuf {
+    channel.output_dir.join(ALL_TIME_DIR)
+}
+
+/// Read a leaderboard as it is in the transaction, which is empty if it was never saved
+fn read_leaderboard(path: &Path, transaction: &Transaction) -> io::Result<Vec<LeaderboardExportItem>> {
+    match transaction.read_to_string(path)? {
+        Some(data) => Ok(serde_json::from_str(&data)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Rebuild the channel's all-time leaderboards from the leaderboards of all its seasons, as they
+/// are in the transaction, staging them in it
+pub fn save_all_time(channel: &Channel, config: &Config, transaction: &Transaction) -> io::Result<()> {
+    let seasons = list_seasons(&channel.output_dir)?;
+    if seasons.is_empty() {
+        return Ok(());
+    }
+    let all_time_dir = all_time_dir(channel);
+
+    for definition in config.leaderboards.boards.iter() {
+        let leaderboard = Leaderboard::unloaded(definition.clone());
+        let mut totals: HashMap<String, LeaderboardExportItem> = HashMap::new();
+        for season in seasons.iter() {
+            let path = leaderboard.get_path(&season_dir(&channel.output_dir, season));
+            for item in read_leaderboard(&path, transaction)? {
+                let gained = item.elo - INITIAL_ELO;
+                match totals.get_mut(&item.id) {
+                    Some(total) => {
+                        total.elo += gained;
+                        total.username = item.username;
+                        total.avatar = item.avatar;
+                        total.badges = item.badges;
+                    }
+                    None => {
+                        totals.insert(item.id.clone(), item);
+                    }
+                }
+            }
+        }
+
+        let path = leaderboard.get_path(&all_time_dir);
+        let previous_ranks: HashMap<String, u32> = read_leaderboard(&path, transaction)?
+            .into_iter()
+            .map(|item| (item.id, item.rank))
+            .collect();
+        let mut items: Vec<LeaderboardExportItem> = totals.into_values().collect();
+        items.sort_by(|a, b| b.elo.total_cmp(&a.elo).then_with(|| a.id.cmp(&b.id)));
+        for (i, item) in items.iter_mut().enumerate() {
+            item.rank = (i + 1) as u32;
+            item.delta = previous_ranks
+                .get(&item.id)
+                .map_or(0, |previous_rank| *previous_rank as i64 - item.rank as i64);
+        }
+
+        transaction.write(&path, serde_json::to_string(&items)?.as_bytes())?;
+        info!(
+            "Saved all-time {} leaderboard of {} seasons",
+            leaderboard.get_name(),
+            seasons.len()
+        );
+    }
+    Ok(())
+}
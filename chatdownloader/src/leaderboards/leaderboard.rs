@@ -0,0 +1,47 @@
+/*
+A leaderboard defined in the config
+*/
+
+use crate::_types::clptypes::UserChatPerformance;
+use crate::_types::leaderboardtypes::LeaderboardInnerState;
+use crate::config::LeaderboardDefinition;
+use crate::leaderboards::leaderboardtrait::AbstractLeaderboard;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct Leaderboard {
+    definition: LeaderboardDefinition,
+    state: HashMap<String, LeaderboardInnerState>,
+}
+
+impl Leaderboard {
+    /// Create the leaderboard, loading its previous state from output_dir if it was saved before
+    pub fn new(definition: LeaderboardDefinition, output_dir: &Path) -> Self {
+        let mut out = Self::unloaded(definition);
+        out.read_initial_state(output_dir);
+        out
+    }
+
+    /// Create the leaderboard without loading its previous state
+    pub fn unloaded(definition: LeaderboardDefinition) -> Self {
+        Self {
+            definition,
+            state: HashMap::new(),
+        }
+    }
+}
+
+impl AbstractLeaderboard for Leaderboard {
+    fn get_name(&self) -> String {
+        self.definition.name.clone()
+    }
+
+    fn __get_state(&mut self) -> &mut HashMap<String, LeaderboardInnerState> {
+        &mut self.state
+    }
+
+    fn calculate_score(&self, performance: &UserChatPerformance) -> Option<f32> {
+        self.definition.score(performance)
+    }
+}
@@ -1,27 +1,42 @@
-use crate::_types::clptypes::{BadgeInformation, UserChatPerformance};
-use crate::_types::leaderboardtypes::{LeaderboardExportItem, LeaderboardInnerState};
-use log::{debug, info};
+use crate::_types::clptypes::{BadgeInformation, StreamInfo, UserChatPerformance};
+use crate::_types::leaderboardtypes::{LeaderboardExportItem, LeaderboardHistoryItem, LeaderboardInnerState};
+use crate::config::Config;
+use crate::leaderboards::transaction::Transaction;
+use log::{debug, info, warn};
 use serde_json::Value;
 use std::collections::HashMap;
 use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-const K: f32 = 2.0;
+/// The ELO of a user the first time they appear on a leaderboard in a season
+pub const INITIAL_ELO: f32 = 1200.0;
 
 pub trait AbstractLeaderboard {
-    fn new() -> Self
-    where
-        Self: Sized;
-
     fn get_name(&self) -> String;
 
     fn __get_state(&mut self) -> &mut HashMap<String, LeaderboardInnerState>;
 
     fn calculate_score(&self, performance: &UserChatPerformance) -> Option<f32>;
 
-    fn read_initial_state(&mut self) {
+    fn get_path(&self, output_dir: &Path) -> PathBuf {
+        output_dir.join(format!("{}.json", self.get_name()))
+    }
+
+    /// Where the configuration that produced the leaderboard is recorded
+    fn get_config_path(&self, output_dir: &Path) -> PathBuf {
+        output_dir.join(format!("{}.config.json", self.get_name()))
+    }
+
+    /// Where every stream's changes to the leaderboard are recorded, one JSON object per line
+    fn get_history_path(&self, output_dir: &Path) -> PathBuf {
+        output_dir.join("history").join(format!("{}.jsonl", self.get_name()))
+    }
+
+    fn read_initial_state(&mut self, output_dir: &Path) {
         info!("Loading {} leaderboard...", self.get_name());
-        let path = format!("{}.json", self.get_name());
-        if !std::path::Path::new(&path).exists() {
+        let path = self.get_path(output_dir);
+        if !path.exists() {
             info!("{} leaderboard doesn't already exist.", self.get_name());
             return;
         }
@@ -40,6 +55,7 @@ pub trait AbstractLeaderboard {
                     previous_rank: Some(export_item.rank),
                     elo: export_item.elo,
                     score: 0.0,
+                    took_part: false,
                 },
             )
         }));
@@ -62,11 +78,12 @@ pub trait AbstractLeaderboard {
                 .or_insert(LeaderboardInnerState {
                     id: performance.id,
                     username: performance.username,
-                    avatar: performance.avatar,
+                    avatar: performance.avatar.clone(),
                     badges: None,
                     previous_rank: None,
-                    elo: 1200.0,
+                    elo: INITIAL_ELO,
                     score: 0.0,
+                    took_part: false,
                 });
 
             let badges: Vec<BadgeInformation> = performance
@@ -75,14 +92,38 @@ pub trait AbstractLeaderboard {
                 .map(|badge_list| badge_list.get_badge_list().unwrap().clone())
                 .unwrap_or_default();
 
+            // Sources that do not know avatars leave them empty, which must not replace a known one
+            if !performance.avatar.is_empty() {
+                entry.avatar = performance.avatar;
+            }
             entry.score = score;
+            entry.took_part = true;
             entry.badges = Some(badges);
         }
     }
 
-    fn save(&mut self) {
+    /// Calculate the new ELOs and stage the leaderboard in the transaction, returning what was saved.
+    /// Fails if it could not be staged, in which case the transaction must not be committed
+    fn save(
+        &mut self,
+        output_dir: &Path,
+        config: &Config,
+        stream: &StreamInfo,
+        transaction: &Transaction,
+    ) -> io::Result<Vec<LeaderboardExportItem>> {
         info!("Saving {} leaderboard...", self.get_name());
-        self.__calculate_new_elo();
+        if self.__get_state().len() < 2 {
+            // Boards come from the config and may match nobody, which must not stop the others
+            warn!("Nothing to save for the {} leaderboard, keeping it as it was", self.get_name());
+            return Ok(Vec::new());
+        }
+        let elos_before: HashMap<String, f32> = self
+            .__get_state()
+            .values()
+            .filter(|state| state.took_part)
+            .map(|state| (state.id.clone(), state.elo))
+            .collect();
+        self.__calculate_new_elo(config.leaderboards.elo_k);
         let to_save: Vec<LeaderboardExportItem> = self
             .__get_state()
             .values()
@@ -99,8 +140,7 @@ pub trait AbstractLeaderboard {
 
         // Update rank and delta
         let mut sorted_to_save = to_save.clone();
-        sorted_to_save.sort_by(|a, b| b.elo.partial_cmp(&a.elo).unwrap());
-        assert!(sorted_to_save.len() > 1, "Nothing to save!");
+        sorted_to_save.sort_by(|a, b| b.elo.partial_cmp(&a.elo).unwrap().then_with(|| a.id.cmp(&b.id)));
 
         let updated_to_save: Vec<LeaderboardExportItem> = sorted_to_save
             .into_iter()
@@ -117,14 +157,49 @@ pub trait AbstractLeaderboard {
             .collect();
 
         // Save to file
-        let path = format!("{}.json", self.get_name());
-        let data =
-            serde_json::to_string(&updated_to_save).expect("Unable to serialize leaderboard data");
-        fs::write(path, data).expect("Unable to write file");
+        let path = self.get_path(output_dir);
+        let data = serde_json::to_string(&updated_to_save)?;
+        transaction.write(&path, data.as_bytes())?;
+        let record = serde_json::to_string_pretty(&config.record())?;
+        transaction.write(&self.get_config_path(output_dir), record.as_bytes())?;
+
+        let history: Vec<LeaderboardHistoryItem> = updated_to_save
+            .iter()
+            .filter_map(|item| {
+                let elo_before = *elos_before.get(&item.id)?;
+                Some(LeaderboardHistoryItem {
+                    stream_id: stream.id.clone(),
+                    started_at: stream.started_at,
+                    id: item.id.clone(),
+                    username: item.username.clone(),
+                    score: self.__get_state()[&item.id].score,
+                    elo_before,
+                    elo_after: item.elo,
+                    rank: item.rank,
+                    delta: item.delta,
+                })
+            })
+            .collect();
+        self.__append_history(output_dir, &history, transaction)?;
         info!("{} leaderboard saved", self.get_name());
+        Ok(updated_to_save)
+    }
+
+    fn __append_history(
+        &self,
+        output_dir: &Path,
+        history: &[LeaderboardHistoryItem],
+        transaction: &Transaction,
+    ) -> io::Result<()> {
+        let mut lines = String::new();
+        for item in history {
+            lines.push_str(&serde_json::to_string(item)?);
+            lines.push('\n');
+        }
+        transaction.append(&self.get_history_path(output_dir), lines.as_bytes())
     }
 
-    fn __calculate_new_elo(&mut self) {
+    fn __calculate_new_elo(&mut self, k: f32) {
         let all_scores: Vec<f32> = self
             .__get_state()
             .values()
@@ -137,7 +212,11 @@ pub trait AbstractLeaderboard {
             .iter()
             .map(|score| {
             let closest_user = self.__get_state().values()
-                .min_by(|a, b| (a.score - score).abs().partial_cmp(&(b.score - score).abs()).unwrap())
+                .min_by(|a, b| {
+                    (a.score - score).abs().partial_cmp(&(b.score - score).abs()).unwrap()
+                        // Ties go to the lowest ID, so the result does not depend on the map's order
+                        .then_with(|| a.id.cmp(&b.id))
+                })
                 .unwrap();
             (*score, closest_user.elo)
             }).collect();
@@ -149,7 +228,7 @@ pub trait AbstractLeaderboard {
             .map(|(sample_score, sample_elo)| {
                 let won = state.score > *sample_score;
                 let p = 1.0 / (1.0 + 10.0_f32.powf((sample_elo - state.elo) / 400.0));
-                K * (won as u8 as f32 - p)
+                k * (won as u8 as f32 - p)
             })
             .sum();
             state.elo += diff;
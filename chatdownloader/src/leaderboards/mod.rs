@@ -1,87 +1,149 @@
-mod bitsonly;
-mod chatonly;
-mod copypastaleaders;
+mod alltime;
+mod leaderboard;
 mod leaderboardtrait;
-mod nonvips;
-mod overall;
-mod subsonly;
+mod transaction;
 
+pub use alltime::{all_time_dir, save_all_time};
+pub use transaction::{recover, rollback, Backup, Transaction};
+
+use futures::future::join_all;
 use futures::join;
+use std::io;
+use std::path::Path;
 
-use log::error;
-use tokio::sync::broadcast;
+use log::info;
+use tokio::sync::mpsc;
 
 use crate::{
-    _types::clptypes::UserChatPerformance, leaderboards::leaderboardtrait::AbstractLeaderboard,
+    _types::clptypes::{Channel, StreamInfo, UserChatPerformance},
+    _types::leaderboardtypes::LeaderboardSnapshot,
+    config::Config,
+    leaderboards::leaderboard::Leaderboard,
+    leaderboards::leaderboardtrait::AbstractLeaderboard,
+    pipeline::FanOut,
 };
 
 async fn calc_leaderboard<M: AbstractLeaderboard + Sync + Send + 'static>(
     leaderboard: &mut M,
-    mut reciever: broadcast::Receiver<UserChatPerformance>,
-) {
+    mut reciever: mpsc::Receiver<UserChatPerformance>,
+    output_dir: &Path,
+    config: &Config,
+    stream: &StreamInfo,
+    transaction: &Transaction,
+) -> io::Result<LeaderboardSnapshot> {
     /*
-    Update the leaderboard based on chat messages sent by a tokio broadcast channel
+    Update the leaderboard with every performance sent until the sender is dropped
     */
-    loop {
-        let user_chat_performance: UserChatPerformance = match reciever.recv().await {
-            Ok(user_chat_performance) => user_chat_performance,
-            Err(_) => break,
-        };
+    while let Some(user_chat_performance) = reciever.recv().await {
         leaderboard.update_leaderboard(user_chat_performance);
     }
-    leaderboard.save();
+    Ok(LeaderboardSnapshot {
+        name: leaderboard.get_name(),
+        items: leaderboard.save(output_dir, config, stream, transaction)?,
+    })
 }
 
 pub struct LeaderboardProcessor {
-    bitsonly: bitsonly::BitsOnly,
-    chatonly: chatonly::ChatOnly,
-    copypasta: copypastaleaders::CopypastaLeaders,
-    nonvips: nonvips::NonVIPS,
-    overall: overall::Overall,
-    subsonly: subsonly::SubsOnly,
+    /*
+    Keeps the leaderboards defined in the config for one channel
+    */
+    channel: Channel,
+    config: Config,
+    leaderboards: Vec<Leaderboard>,
 }
 
 impl LeaderboardProcessor {
-    pub fn new() -> Self {
-        let bitsonly = bitsonly::BitsOnly::new();
-        let chatonly = chatonly::ChatOnly::new();
-        let copypasta = copypastaleaders::CopypastaLeaders::new();
-        let nonvips = nonvips::NonVIPS::new();
-        let overall = overall::Overall::new();
-        let subsonly = subsonly::SubsOnly::new();
+    /// Load the leaderboards of the channel's current season
+    pub fn new(channel: &Channel, config: &Config) -> Self {
+        let output_dir = channel.leaderboard_dir();
+        let output_dir = output_dir.as_path();
+        let leaderboards = config
+            .leaderboards
+            .boards
+            .iter()
+            .map(|definition| Leaderboard::new(definition.clone(), output_dir))
+            .collect();
 
         Self {
-            bitsonly,
-            chatonly,
-            copypasta,
-            nonvips,
-            overall,
-            subsonly,
+            channel: channel.clone(),
+            config: config.clone(),
+            leaderboards,
         }
     }
 
-    pub async fn run(&mut self, performances: Vec<UserChatPerformance>) {
-        let (broadcast_sender, broadcast_reciever) = broadcast::channel(100000);
+    /// Add the performances in a stream to every leaderboard and stage them, along with the
+    /// all-time leaderboards, in the transaction. Returns the staged leaderboards, leaving out
+    /// those that had nothing to save. If it fails, the transaction must not be committed
+    pub async fn run(
+        &mut self,
+        performances: Vec<UserChatPerformance>,
+        stream: &StreamInfo,
+        transaction: &Transaction,
+    ) -> io::Result<Vec<LeaderboardSnapshot>> {
+        info!("Updating the leaderboards of channel {}", self.channel.id);
+        let output_dir = self.channel.leaderboard_dir();
+        let output_dir = output_dir.as_path();
+        let config = &self.config;
+
+        // Every leaderboard has a bounded channel, so none of them can fall behind and miss performances
+        let mut fan_out = FanOut::default();
+        let leaderboards = self.leaderboards.iter_mut().map(|leaderboard| {
+            let reciever = fan_out.subscribe(format!("Leaderboard {}", leaderboard.get_name()));
+            calc_leaderboard(leaderboard, reciever, output_dir, config, stream, transaction)
+        });
+        let leaderboards = join_all(leaderboards.collect::<Vec<_>>());
+        let (sent, snapshots) = join!(send_performances(fan_out, performances), leaderboards);
+        sent.map_err(io::Error::other)?;
+        let snapshots = snapshots.into_iter().collect::<io::Result<Vec<_>>>()?;
 
-        join!(
-            send_performances(broadcast_sender, performances),
-            calc_leaderboard(&mut self.bitsonly, broadcast_reciever.resubscribe()),
-            calc_leaderboard(&mut self.chatonly, broadcast_reciever.resubscribe()),
-            calc_leaderboard(&mut self.copypasta, broadcast_reciever.resubscribe()),
-            calc_leaderboard(&mut self.nonvips, broadcast_reciever.resubscribe()),
-            calc_leaderboard(&mut self.overall, broadcast_reciever.resubscribe()),
-            calc_leaderboard(&mut self.subsonly, broadcast_reciever.resubscribe()),
-        );
+        if self.channel.season.is_some() {
+            save_all_time(&self.channel, config, transaction)?;
+        }
+        Ok(snapshots
+            .into_iter()
+            .filter(|snapshot| !snapshot.items.is_empty())
+            .collect())
+    }
+
+    /// Delete the channel's leaderboards of the current season, so the next run starts from a
+    /// blank state, in one transaction backed up as given. With a season, the all-time
+    /// leaderboards are deleted too. They are rebuilt from every season the next time a
+    /// season's leaderboards are saved, without ranks to compare against
+    pub fn clear(channel: &Channel, config: &Config, backup: Backup) -> io::Result<()> {
+        let mut transaction = Transaction::begin(&channel.output_dir)?;
+        Self::clear_saved(&channel.leaderboard_dir(), config, &mut transaction)?;
+        if channel.season.is_some() {
+            Self::clear_saved(&all_time_dir(channel), config, &mut transaction)?;
+        }
+        transaction.commit_with(backup)
+    }
+
+    /// Stage the deletion of the saved leaderboards in output_dir
+    fn clear_saved(output_dir: &Path, config: &Config, transaction: &mut Transaction) -> io::Result<()> {
+        let paths = config.leaderboards.boards.iter().flat_map(|definition| {
+            let leaderboard = Leaderboard::unloaded(definition.clone());
+            [
+                leaderboard.get_path(output_dir),
+                leaderboard.get_config_path(output_dir),
+                leaderboard.get_history_path(output_dir),
+            ]
+        });
+
+        for path in paths.filter(|path| path.exists()) {
+            info!("Removing {}", path.display());
+            transaction.remove(&path)?;
+        }
+        Ok(())
     }
 }
 
+/// Send the performances to every leaderboard. Dropping fan_out afterwards tells them there are no more
 pub async fn send_performances(
-    sender: broadcast::Sender<UserChatPerformance>,
+    fan_out: FanOut<UserChatPerformance>,
     performances: Vec<UserChatPerformance>,
-) {
+) -> Result<(), String> {
     for performance in performances {
-        if let Err(e) = sender.send(performance) {
-            error!("Error sending performance to leaderboards: {}", e);
-        }
+        fan_out.send(performance).await?;
     }
+    Ok(())
 }
@@ -0,0 +1,370 @@
+/*
+Crash-safe updates of the files a stream changes, e.g. every leaderboard
+of a channel along with its all-time leaderboards and the stream's reports.
+
+Files are staged in <root>/.pending/ and only moved into place once all
+of them are written. The commit point is the atomic rename of the
+manifest listing them: a crash before it leaves the live files as they
+were and the staged ones are discarded, a crash after it is finished by
+moving the remaining files the next time the root is opened.
+
+Each commit keeps the files it replaced in <root>/backups/<generation>/,
+the last BACKUPS_KEPT generations, so an update can be rolled back. A
+commit may instead add them to the latest generation, so a series of
+commits, e.g. replaying a season, is rolled back at once
+*/
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const PENDING_DIR: &str = ".pending";
+const BACKUPS_DIR: &str = "backups";
+const FILES_DIR: &str = "files";
+/// Where the backup of a transaction is staged, below PENDING_DIR
+const STAGED_BACKUP_DIR: &str = "backup";
+const MANIFEST_FILE: &str = "manifest.json";
+const BACKUP_RECORD_FILE: &str = "backup.json";
+/// How many previous generations are kept to roll back to
+const BACKUPS_KEPT: usize = 10;
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Manifest {
+    /*
+    A committed transaction, with paths relative to the root
+    */
+    /// Staged files to move into place
+    writes: Vec<PathBuf>,
+    /// Files to delete
+    removes: Vec<PathBuf>,
+    /// The generation the staged backup is kept as
+    backup: Option<u32>,
+    /// Whether the staged backup is added to the existing generation instead of starting it
+    #[serde(default)]
+    extends_backup: bool,
+    /// A generation to delete, after rolling back to it
+    discard: Option<u32>,
+}
+
+/// How a commit backs up the files it replaces
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backup {
+    /// As a new generation
+    Generation,
+    /// As part of the latest generation, keeping the files it already holds. Rolling it back
+    /// then also undoes this commit. Starts a generation if there is none
+    ExtendLatest,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct BackupRecord {
+    /*
+    Describes a backup generation, next to the files it replaced
+    */
+    generation: u32,
+    created_at: DateTime<Utc>,
+    /// Files the generation created, which did not exist before it
+    created: Vec<PathBuf>,
+}
+
+pub struct Transaction {
+    /*
+    Files staged to replace or remove files below the root
+    */
+    root: PathBuf,
+    removes: Vec<PathBuf>,
+}
+
+impl Transaction {
+    /// Start a transaction on the files below root, first finishing or discarding one that
+    /// was interrupted
+    pub fn begin(root: &Path) -> io::Result<Self> {
+        recover(root)?;
+        fs::create_dir_all(root.join(PENDING_DIR).join(FILES_DIR))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            removes: Vec::new(),
+        })
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> io::Result<&'a Path> {
+        path.strip_prefix(&self.root).map_err(|_| {
+            io::Error::other(format!(
+                "{} is outside of {}",
+                path.display(),
+                self.root.display()
+            ))
+        })
+    }
+
+    fn staged_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let staged = self
+            .root
+            .join(PENDING_DIR)
+            .join(FILES_DIR)
+            .join(self.relative(path)?);
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(staged)
+    }
+
+    /// Stage the new contents of the file at path
+    pub fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut file = File::create(self.staged_path(path)?)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    }
+
+    /// Stage contents added to the end of the file at path
+    pub fn append(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let staged = self.staged_path(path)?;
+        if !staged.exists() && path.exists() {
+            fs::copy(path, &staged)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(staged)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    }
+
+    /// Stage the removal of the file at path
+    pub fn remove(&mut self, path: &Path) -> io::Result<()> {
+        let relative = self.relative(path)?.to_path_buf();
+        self.removes.push(relative);
+        Ok(())
+    }
+
+    /// The file at path as it is in the transaction, or None if it does not exist
+    pub fn read_to_string(&self, path: &Path) -> io::Result<Option<String>> {
+        let staged = self
+            .root
+            .join(PENDING_DIR)
+            .join(FILES_DIR)
+            .join(self.relative(path)?);
+        for candidate in [&staged, path] {
+            if candidate.exists() {
+                return fs::read_to_string(candidate).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Back up the files the transaction replaces as a new generation, then move every staged
+    /// file into place
+    pub fn commit(self) -> io::Result<()> {
+        self.finish(Some(Backup::Generation), None)
+    }
+
+    /// Like commit, backing up the files the transaction replaces as given
+    pub fn commit_with(self, backup: Backup) -> io::Result<()> {
+        self.finish(Some(backup), None)
+    }
+
+    fn finish(self, backup: Option<Backup>, discard: Option<u32>) -> io::Result<()> {
+        let pending_dir = self.root.join(PENDING_DIR);
+        let writes = list_files(&pending_dir.join(FILES_DIR))?;
+        let latest = generations(&self.root)?.last().copied();
+        let (backup, extends_backup) = match (backup, latest) {
+            (None, _) => (None, false),
+            (Some(Backup::ExtendLatest), Some(latest)) => (Some(latest), true),
+            (Some(_), latest) => (Some(latest.map_or(1, |last| last + 1)), false),
+        };
+        if let Some(generation) = backup {
+            let generation_dir = self.root.join(BACKUPS_DIR).join(generation.to_string());
+            let mut record = if extends_backup {
+                serde_json::from_str(&fs::read_to_string(generation_dir.join(BACKUP_RECORD_FILE))?)?
+            } else {
+                BackupRecord {
+                    generation,
+                    created_at: Utc::now(),
+                    created: Vec::new(),
+                }
+            };
+            let backup_dir = pending_dir.join(STAGED_BACKUP_DIR);
+            let changed: BTreeSet<&PathBuf> = writes.iter().chain(self.removes.iter()).collect();
+            for relative in changed {
+                // An extended generation keeps the file as it was before its first commit
+                let held = generation_dir.join(FILES_DIR).join(relative).exists()
+                    || record.created.contains(relative);
+                if extends_backup && held {
+                    continue;
+                }
+                let live = self.root.join(relative);
+                if live.exists() {
+                    let backed_up = backup_dir.join(FILES_DIR).join(relative);
+                    if let Some(parent) = backed_up.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::copy(&live, backed_up)?;
+                } else {
+                    record.created.push(relative.clone());
+                }
+            }
+            fs::create_dir_all(&backup_dir)?;
+            fs::write(
+                backup_dir.join(BACKUP_RECORD_FILE),
+                serde_json::to_string_pretty(&record)?,
+            )?;
+        }
+
+        let manifest = Manifest {
+            writes,
+            removes: self.removes,
+            backup,
+            extends_backup,
+            discard,
+        };
+        // Writing the manifest commits the transaction
+        let staged_manifest = pending_dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&staged_manifest)?;
+        file.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(staged_manifest, pending_dir.join(MANIFEST_FILE))?;
+
+        apply(&self.root, &manifest)
+    }
+}
+
+/// Every file below dir, relative to it
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        if !current.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if let Ok(relative) = path.strip_prefix(dir) {
+                files.push(relative.to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The backup generations below root, oldest first
+fn generations(root: &Path) -> io::Result<Vec<u32>> {
+    let backups_dir = root.join(BACKUPS_DIR);
+    if !backups_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(backups_dir)? {
+        if let Ok(generation) = entry?.file_name().to_string_lossy().parse::<u32>() {
+            generations.push(generation);
+        }
+    }
+    generations.sort();
+    Ok(generations)
+}
+
+/// Carry out a committed transaction. Steps already done by an interrupted attempt are skipped
+fn apply(root: &Path, manifest: &Manifest) -> io::Result<()> {
+    let pending_dir = root.join(PENDING_DIR);
+    let backups_dir = root.join(BACKUPS_DIR);
+    if let Some(generation) = manifest.backup {
+        let backup_dir = pending_dir.join(STAGED_BACKUP_DIR);
+        let generation_dir = backups_dir.join(generation.to_string());
+        if manifest.extends_backup && backup_dir.exists() {
+            for relative in list_files(&backup_dir.join(FILES_DIR))? {
+                let held = generation_dir.join(FILES_DIR).join(&relative);
+                if let Some(parent) = held.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(backup_dir.join(FILES_DIR).join(&relative), held)?;
+            }
+            let record = backup_dir.join(BACKUP_RECORD_FILE);
+            if record.exists() {
+                fs::rename(record, generation_dir.join(BACKUP_RECORD_FILE))?;
+            }
+        } else if backup_dir.exists() {
+            fs::create_dir_all(&backups_dir)?;
+            fs::rename(backup_dir, generation_dir)?;
+        }
+    }
+    for relative in manifest.writes.iter() {
+        let staged = pending_dir.join(FILES_DIR).join(relative);
+        if staged.exists() {
+            let live = root.join(relative);
+            if let Some(parent) = live.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(staged, live)?;
+        }
+    }
+    for relative in manifest.removes.iter() {
+        let live = root.join(relative);
+        if live.exists() {
+            fs::remove_file(live)?;
+        }
+    }
+    if let Some(generation) = manifest.discard {
+        let discarded = backups_dir.join(generation.to_string());
+        if discarded.exists() {
+            fs::remove_dir_all(discarded)?;
+        }
+    }
+    fs::remove_dir_all(&pending_dir)?;
+
+    let generations = generations(root)?;
+    let expired = generations.len().saturating_sub(BACKUPS_KEPT);
+    for generation in generations.into_iter().take(expired) {
+        fs::remove_dir_all(backups_dir.join(generation.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Finish a transaction below root that was committed but interrupted, or discard one that
+/// was never committed
+pub fn recover(root: &Path) -> io::Result<()> {
+    let pending_dir = root.join(PENDING_DIR);
+    if !pending_dir.exists() {
+        return Ok(());
+    }
+    let manifest_path = pending_dir.join(MANIFEST_FILE);
+    if manifest_path.exists() {
+        warn!("Finishing an interrupted update of {}", root.display());
+        let manifest: Manifest = serde_json::from_str(&fs::read_to_string(manifest_path)?)?;
+        apply(root, &manifest)
+    } else {
+        warn!("Discarding an unfinished update of {}", root.display());
+        fs::remove_dir_all(pending_dir)
+    }
+}
+
+/// Restore the files below root to how they were before the commits of the latest backup
+/// generation, and drop it. Returns the generation that was undone, or None if there are no backups
+pub fn rollback(root: &Path) -> io::Result<Option<u32>> {
+    recover(root)?;
+    let Some(generation) = generations(root)?.last().copied() else {
+        return Ok(None);
+    };
+    let backup_dir = root.join(BACKUPS_DIR).join(generation.to_string());
+    let record: BackupRecord =
+        serde_json::from_str(&fs::read_to_string(backup_dir.join(BACKUP_RECORD_FILE))?)?;
+
+    let mut transaction = Transaction::begin(root)?;
+    for relative in list_files(&backup_dir.join(FILES_DIR))? {
+        let contents = fs::read(backup_dir.join(FILES_DIR).join(&relative))?;
+        transaction.write(&root.join(&relative), &contents)?;
+    }
+    for relative in record.created.iter() {
+        transaction.remove(&root.join(relative))?;
+    }
+    // The rollback is not backed up itself, so rolling back again goes further back
+    transaction.finish(None, Some(generation))?;
+    info!(
+        "Rolled {} back to before the update of {}",
+        root.display(),
+        record.created_at
+    );
+    Ok(Some(generation))
+}
@@ -0,0 +1,34 @@
+/*
+The Twitch badges and 7TV emotes a stream is scored with.
+
+They change over time, so an online run saves the ones it looked up
+next to the stream's archived chat log, and an offline run of the same
+stream loads them to reproduce its scores
+*/
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::_types::clptypes::BadgeInformation;
+use crate::metrics::emote::{fetch_7tv_emotes, SevenTVEmote};
+use crate::twitch_utils::TwitchAPIWrapper;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Lookups {
+    /// Badge versions by badge set ID, global and channel badges alike
+    pub badges: HashMap<String, HashMap<String, BadgeInformation>>,
+    /// The channel's 7TV emotes. Empty if 7TV could not be reached
+    pub seventv_emotes: Vec<SevenTVEmote>,
+}
+
+impl Lookups {
+    /// Look up the channel's current badges and 7TV emotes
+    pub async fn fetch(twitch: &TwitchAPIWrapper, channel_id: &str) -> Self {
+        info!("Looking up the badges and 7TV emotes of channel {}", channel_id);
+        Self {
+            badges: twitch.get_badges(channel_id.to_string()).await.unwrap(),
+            seventv_emotes: fetch_7tv_emotes(channel_id).await,
+        }
+    }
+}
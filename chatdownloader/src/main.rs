@@ -2,43 +2,368 @@ mod _constants;
 mod _types;
 mod backfill;
 mod chatlogprocessor;
+mod chatsources;
+mod cli;
+mod config;
+#[cfg(feature = "sqlite")]
+mod database;
+mod eventsub;
 mod leaderboards;
+mod lookups;
 mod metadata;
 mod metrics;
+mod pipeline;
+mod seasons;
 mod twitch_utils;
-mod twitchdownloaderproxy;
 
+use chrono::Utc;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 use env_logger::Env;
-use log::info;
-use std::{env, process::exit};
+use log::{error, info, warn};
+use std::fs;
+use std::time::Duration;
+
+use crate::_types::clptypes::Channel;
+use crate::chatlogprocessor::ChatLogProcessor;
+use crate::chatsources::archive::{ArchivedChatSource, ChatArchive};
+use crate::chatsources::chatsourcetrait::ChatSource;
+use crate::chatsources::gql::GqlChatSource;
+use crate::chatsources::irc;
+use crate::chatsources::localfile::LocalChatSource;
+use crate::chatsources::twitchdownloaderproxy::TwitchChatDownloader;
+use crate::cli::{validate_season, ChatSourceKind, Cli, Commands, GlobalArgs};
+use crate::config::Config;
+use crate::leaderboards::Backup;
+use crate::twitch_utils::TwitchAPIWrapper;
 
 #[tokio::main]
-async fn main() {
-    let env = Env::default()
-        .filter_or("MY_LOG_LEVEL", "info")
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cli = Cli::parse();
+
+    let env = Env::new()
+        .default_filter_or(cli.global.log_level.clone())
         .write_style_or("MY_LOG_STYLE", "always");
 
     env_logger::init_from_env(env);
 
-    if env::var("BACKFILL").as_deref() == Ok("1") {
-        backfill::backfill().await;
-        exit(0);
+    let config = match &cli.global.config {
+        Some(path) => Config::load(path)
+            .unwrap_or_else(|e| Cli::command().error(ErrorKind::InvalidValue, e).exit()),
+        None => Config::default(),
+    };
+
+    if let Err(e) = cli.global.resolve_season() {
+        Cli::command().error(ErrorKind::InvalidValue, e).exit()
+    }
+
+    fs::create_dir_all(&cli.global.output_dir)?;
+    for channel in cli.global.channels() {
+        fs::create_dir_all(channel.leaderboard_dir())?;
+        // Finish or undo an update that a crash interrupted before anything reads the leaderboards
+        leaderboards::recover(&channel.output_dir)?;
+        // Downloading leaves the leaderboards untouched, and rolling back may reopen a season
+        if !matches!(cli.command, Commands::Download { .. } | Commands::Rollback) {
+            seasons::ensure_open(&channel)?;
+        }
+    }
+
+    match cli.command {
+        Commands::ProcessFile { chat_file, offline } => {
+            let channel = cli.global.single_channel()?;
+            info!("Processing chat log file: {}", chat_file.display());
+            let twitch = if offline {
+                info!("Running offline, skipping Twitch authentication");
+                None
+            } else {
+                Some(authenticate().await?)
+            };
+            let stream_id = chat_file
+                .file_stem()
+                .map_or("chat".into(), |stem| stem.to_string_lossy());
+            // Lookups are saved in the archive under the file's name, like those of a VOD
+            let archive = cli.global.archive_dir.clone().map(ChatArchive::new);
+            let processor = match (&twitch, &archive) {
+                (Some(twitch), _) => ChatLogProcessor::new(twitch, &channel, &config),
+                (None, Some(archive)) => ChatLogProcessor::offline(&channel, &config)
+                    .with_lookups(archive.read_lookups(&stream_id)?),
+                (None, None) => ChatLogProcessor::offline(&channel, &config),
+            };
+            let processed = processor.parse(&chat_file).await?;
+            // Processing the same file again dates a stream nobody chatted in the same way
+            let stream = processed.info(&stream_id, fs::metadata(&chat_file)?.modified()?.into());
+            if let (Some(_), Some(archive), Some(lookups)) = (&twitch, &archive, &processed.lookups) {
+                archive.store_lookups(&stream.id, lookups)?;
+            }
+            ChatLogProcessor::export(
+                processed.performances,
+                processed.reports,
+                &stream,
+                &channel,
+                &config,
+                Backup::Generation,
+            )
+            .await?;
+        }
+        Commands::Live {
+            channel_login,
+            irc_addr,
+            offline,
+        } => {
+            let channel = cli.global.single_channel()?;
+            let twitch = if offline { None } else { Some(authenticate().await?) };
+            let channel_login = match (&twitch, channel_login) {
+                (Some(twitch), channel_login) => {
+                    let login = twitch.get_login(&channel.id).await?;
+                    if let Some(given) = channel_login.filter(|given| !given.eq_ignore_ascii_case(&login)) {
+                        return Err(format!("Channel {} is {}, not {}", channel.id, login, given).into());
+                    }
+                    login
+                }
+                (None, Some(channel_login)) => channel_login,
+                (None, None) => return Err("--channel-login is needed with --offline".into()),
+            };
+            let processor = match &twitch {
+                Some(twitch) => ChatLogProcessor::new(twitch, &channel, &config),
+                None => ChatLogProcessor::offline(&channel, &config),
+            };
+            let session_started_at = Utc::now();
+            let stream_id = format!("live-{}", session_started_at.format("%Y%m%dT%H%M%SZ"));
+            let comment_stream = irc::live_comment_stream(irc_addr, channel_login);
+            let processed = processor.parse_from_stream(comment_stream).await?;
+            let stream = processed.info(&stream_id, session_started_at);
+            ChatLogProcessor::export(
+                processed.performances,
+                processed.reports,
+                &stream,
+                &channel,
+                &config,
+                Backup::Generation,
+            )
+            .await?;
+        }
+        Commands::CloseSeason { next } => {
+            if let Err(e) = validate_season(&next) {
+                Cli::command().error(ErrorKind::InvalidValue, e).exit()
+            }
+            for channel in cli.global.channels() {
+                seasons::close_season(&channel, &next, &config)?;
+            }
+            let season_file = &cli.global.season_file;
+            if season_file.exists() {
+                fs::write(season_file, format!("{}\n", next))?;
+                info!("Wrote season {} to {}", next, season_file.display());
+            } else {
+                info!("Pass --season {} to score the new season", next);
+            }
+        }
+        Commands::Rollback => {
+            for channel in cli.global.channels() {
+                match leaderboards::rollback(&channel.output_dir)? {
+                    Some(generation) => info!(
+                        "Rolled back update {} of the leaderboards of channel {}",
+                        generation, channel.id
+                    ),
+                    None => warn!("No earlier leaderboards of channel {} to roll back to", channel.id),
+                }
+            }
+        }
+        command => run_online(command, &cli.global, &config).await?,
     }
 
+    Ok(())
+}
+
+async fn authenticate() -> Result<TwitchAPIWrapper, Box<dyn std::error::Error>> {
     info!("Authenticating with Twitch...");
+    TwitchAPIWrapper::new().await
+}
 
-    let twitch = twitch_utils::TwitchAPIWrapper::new().await.unwrap();
-    let vod_id = twitch
-        .get_latest_vod_id(_constants::VED_CH_ID.to_string())
+/// Run the commands that pull chat logs from the configured chat source
+async fn run_online(
+    command: Commands,
+    global: &GlobalArgs,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match global.source {
+        ChatSourceKind::TwitchDownloader => {
+            let mut source = TwitchChatDownloader::new(
+                global.twitchdownloader_version.clone(),
+                global.twitchdownloader_sha256.clone(),
+                global
+                    .twitchdownloader_cache_dir
+                    .clone()
+                    .unwrap_or_else(TwitchChatDownloader::default_cache_dir),
+            )?;
+            run_with_source(command, global, config, &mut source).await
+        }
+        ChatSourceKind::Local => {
+            let chat_dir = global
+                .chat_dir
+                .clone()
+                .expect("--chat-dir is required by the local source");
+            run_with_source(command, global, config, &mut LocalChatSource::new(chat_dir)).await
+        }
+        ChatSourceKind::Gql => {
+            let mut source =
+                GqlChatSource::new(global.gql_endpoint.clone(), global.checkpoint_dir.clone());
+            run_with_source(command, global, config, &mut source).await
+        }
+    }
+}
+
+/// Run the command, archiving the chat logs read from source if there is an archive directory
+async fn run_with_source<S: ChatSource>(
+    command: Commands,
+    global: &GlobalArgs,
+    config: &Config,
+    source: &mut S,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match &global.archive_dir {
+        Some(archive_dir) => {
+            let archive = ChatArchive::new(archive_dir.clone());
+            run_commands(command, global, config, &mut ArchivedChatSource::new(source, archive)).await
+        }
+        None => run_commands(command, global, config, source).await,
+    }
+}
+
+async fn run_commands<S: ChatSource>(
+    command: Commands,
+    global: &GlobalArgs,
+    config: &Config,
+    source: &mut S,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output_dir = global.output_dir.as_path();
+    let archive = global.archive_dir.clone().map(ChatArchive::new);
+
+    if let Commands::Download { vod_ids } = command {
+        for vod_id in vod_ids.iter() {
+            let chat_log = source.get_chat_log(vod_id).await?;
+            let path = output_dir.join(format!("{}.json", vod_id));
+            fs::write(&path, serde_json::to_string(&chat_log)?)?;
+            info!("Saved chat log for {} to {}", vod_id, path.display());
+        }
+        return Ok(());
+    }
+
+    if let Commands::Daemon {
+        listen_addr,
+        eventsub_secret,
+        callback_url,
+        vod_delay_secs,
+    } = command
+    {
+        let channels = global.channels();
+        let channel_ids = channels.iter().map(|channel| channel.id.clone()).collect();
+        let mut offline_events =
+            eventsub::serve(&listen_addr, eventsub_secret.clone(), channel_ids).await?;
+        if let Some(callback_url) = callback_url {
+            let twitch = authenticate().await?;
+            for channel in channels.iter() {
+                twitch
+                    .subscribe_stream_offline(
+                        channel.id.clone(),
+                        callback_url.clone(),
+                        eventsub_secret.clone(),
+                    )
+                    .await?;
+            }
+        }
+
+        while let Some(broadcaster_id) = offline_events.recv().await {
+            let Some(channel) = channels.iter().find(|channel| channel.id == broadcaster_id) else {
+                continue;
+            };
+            info!(
+                "Stream of {} ended, processing its VOD in {} seconds",
+                channel.id, vod_delay_secs
+            );
+            tokio::time::sleep(Duration::from_secs(vod_delay_secs)).await;
+            // Authenticate for every stream, the daemon may outlive an app access token
+            let twitch = match authenticate().await {
+                Ok(twitch) => twitch,
+                Err(e) => {
+                    error!(
+                        "Could not authenticate to process the latest VOD of {}: {}",
+                        channel.id, e
+                    );
+                    continue;
+                }
+            };
+            if let Err(e) = process_latest(&twitch, channel, archive.as_ref(), config, source).await {
+                error!("Failed to process the latest VOD of {}: {}", channel.id, e);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Commands::Recompute {
+        from_file,
+        offline,
+        verify,
+    } = command
+    {
+        let channel = global.single_channel()?;
+        let vod_ids = match (&from_file, &archive) {
+            (Some(from_file), _) => backfill::read_video_ids(from_file)?,
+            (None, Some(archive)) => archive.vod_ids_in_broadcast_order()?,
+            (None, None) => return Err("Recompute needs --from-file or --archive-dir".into()),
+        };
+        let twitch = if offline { None } else { Some(authenticate().await?) };
+        return backfill::recompute(
+            twitch.as_ref(),
+            source,
+            archive.as_ref(),
+            &channel,
+            config,
+            &vod_ids,
+            verify,
+        )
         .await;
+    }
 
-    info!("Script triggered, pulling logs for VOD ID: {}...", vod_id);
+    let twitch = &authenticate().await?;
 
-    let mut downloader = twitchdownloaderproxy::TwitchChatDownloader::new();
-    let chat_log = downloader.download_chat(&vod_id).await.expect("Failed to download chat: {e:?}");
+    match command {
+        Commands::RunLatest => {
+            for channel in global.channels().iter() {
+                process_latest(twitch, channel, archive.as_ref(), config, source).await?;
+            }
+        }
+        Commands::Process { vod_ids } => {
+            let channel = global.single_channel()?;
+            backfill::backfill(Some(twitch), source, archive.as_ref(), &channel, config, &vod_ids).await?;
+        }
+        Commands::Backfill { from_file } => {
+            let channel = global.single_channel()?;
+            let vod_ids = backfill::read_video_ids(&from_file)?;
+            backfill::backfill(Some(twitch), source, archive.as_ref(), &channel, config, &vod_ids).await?;
+        }
+        Commands::ProcessFile { .. }
+        | Commands::Live { .. }
+        | Commands::Daemon { .. }
+        | Commands::Download { .. }
+        | Commands::Recompute { .. }
+        | Commands::CloseSeason { .. }
+        | Commands::Rollback => {
+            unreachable!("handled before reaching this point")
+        }
+    }
 
-    let processor = chatlogprocessor::ChatLogProcessor::new(&twitch);
-    // let chat_log = processor.__parse_to_log_struct("chat.json".to_string());
-    let user_performances = processor.parse_from_log_object(chat_log).await;
-    chatlogprocessor::ChatLogProcessor::export_to_leaderboards(user_performances).await;
+    Ok(())
+}
+
+/// Process the channel's most recent VOD
+async fn process_latest<S: ChatSource>(
+    twitch: &TwitchAPIWrapper,
+    channel: &Channel,
+    archive: Option<&ChatArchive>,
+    config: &Config,
+    source: &mut S,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let vod_id = twitch.get_latest_vod_id(channel.id.clone()).await;
+
+    info!("Script triggered, pulling logs for VOD ID: {}...", vod_id);
+    backfill::backfill(Some(twitch), source, archive, channel, config, &[vod_id]).await
 }
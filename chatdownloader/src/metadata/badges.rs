@@ -1,22 +1,44 @@
 /*
 Assigns badges to each user
 */
-use log::error;
+use chrono::{DateTime, Utc};
+use log::{error, info};
 use std::collections::HashMap;
 
-use crate::_constants::VED_CH_ID;
-use crate::_types::clptypes::{BadgeInformation, MetadataTypes, MetadataUpdate};
+use crate::_types::clptypes::{BadgeInformation, MergeRule, MetadataTypes, MetadataUpdate};
 use crate::_types::twitchtypes::Comment;
+use crate::lookups::Lookups;
 use crate::metadata::metadatatrait::AbstractMetadata;
 use crate::twitch_utils::TwitchAPIWrapper;
 
 pub struct Badges {
-    badges: HashMap<String, HashMap<String, BadgeInformation>>,
+    /// None when running offline without saved lookups, in which case every user gets an
+    /// empty badge list
+    badges: Option<HashMap<String, HashMap<String, BadgeInformation>>>,
+}
+
+impl Badges {
+    /// Badges resolved with looked up or saved badges. Without them every user gets an empty
+    /// badge list
+    pub fn from_lookups(lookups: Option<&Lookups>) -> Self {
+        if lookups.is_none() {
+            info!("No badges were looked up, badges will not be resolved");
+        }
+        Self {
+            badges: lookups.map(|lookups| lookups.badges.clone()),
+        }
+    }
 }
 
 impl AbstractMetadata for Badges {
-    async fn new(twitch: &TwitchAPIWrapper) -> Self {
-        let badges = twitch.get_badges(VED_CH_ID.to_string()).await.unwrap();
+    async fn new(twitch: Option<&TwitchAPIWrapper>, channel_id: &str) -> Self {
+        let badges = match twitch {
+            Some(twitch) => Some(twitch.get_badges(channel_id.to_string()).await.unwrap()),
+            None => {
+                info!("Running offline, badges will not be resolved");
+                None
+            }
+        };
         Self { badges }
     }
 
@@ -28,15 +50,19 @@ impl AbstractMetadata for Badges {
         MetadataTypes::BadgeList(vec![])
     }
 
+    /// Badges seen on any message are kept, with the latest version of each
+    fn get_merge_rule(&self) -> MergeRule {
+        MergeRule::Union
+    }
+
     fn get_metadata(
         &self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetadataUpdate {
         let mut metadata: Vec<BadgeInformation> = vec![];
-        let user_badges = if let Some(user_badges) = comment.message.user_badges {
-            user_badges
-        } else {
+        let (Some(user_badges), Some(badges)) = (&comment.message.user_badges, &self.badges) else {
             let mut out: HashMap<String, MetadataTypes> = HashMap::new();
             out.insert(
                 comment.commenter._id.clone(),
@@ -48,7 +74,7 @@ impl AbstractMetadata for Badges {
             };
         };
         for badge in user_badges {
-            let badge_set = self.badges.get(&badge._id);
+            let badge_set = badges.get(&badge._id);
             if badge_set.is_none() {
                 error!("Badge set not found for badge id {}", badge._id);
                 continue;
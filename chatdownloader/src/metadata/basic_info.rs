@@ -2,6 +2,7 @@
 Get the username and avatar of the user
 */
 
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 
 use crate::_types::clptypes::{MetadataTypes, MetadataUpdate};
@@ -17,7 +18,7 @@ impl AbstractMetadata for BasicInfo {
     Figures out if the user is a special role
     */
 
-    async fn new(_twitch: &TwitchAPIWrapper) -> Self {
+    async fn new(_twitch: Option<&TwitchAPIWrapper>, _channel_id: &str) -> Self {
         Self
     }
 
@@ -31,8 +32,9 @@ impl AbstractMetadata for BasicInfo {
 
     fn get_metadata(
         &self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetadataUpdate {
         let mut metadata: HashMap<String, MetadataTypes> = HashMap::new();
         metadata.insert(
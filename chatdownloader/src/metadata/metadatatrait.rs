@@ -2,25 +2,35 @@
 Represents an abstract metadata
 */
 
-use crate::_types::clptypes::{MetadataTypes, MetadataUpdate};
+use chrono::{DateTime, Utc};
+
+use crate::_types::clptypes::{MergeRule, MetadataTypes, MetadataUpdate};
 use crate::_types::twitchtypes::Comment;
 use crate::twitch_utils::TwitchAPIWrapper;
 
-pub trait AbstractMetadata: Sized {
+pub trait AbstractMetadata {
     /*
     Structs that implement this trait represent a piece of metadata
 
     Struct should ensure to set self.twtich to the twitch object passed
     if it needs to make API calls
+
+    Apart from new, every method can be called on a boxed
+    dyn AbstractMetadata, which is how MetadataProcessor holds them.
+    New metadata is registered in metadata::create_metadata and
+    metadata::METADATA_NAMES
     */
 
-    async fn new(twitch: &TwitchAPIWrapper) -> Self
+    async fn new(twitch: Option<&TwitchAPIWrapper>, channel_id: &str) -> Self
     where
         Self: Sized + Send;
     /*
     Create a new metadata object
 
-    :param twitch: A TwitchAPIWrapper object
+    :param twitch: A TwitchAPIWrapper object, or None when running
+                   offline. Metadata that needs the API should fall
+                   back to its default value in that case
+    :param channel_id: The Twitch channel whose chat is being scored
     */
 
     fn get_name(&self) -> String;
@@ -33,15 +43,25 @@ pub trait AbstractMetadata: Sized {
     Get the default value for this metadata
     */
 
+    fn get_merge_rule(&self) -> MergeRule {
+        /*
+        How the updates from several comments by the same user are
+        combined. The first update always replaces the default value
+        */
+        MergeRule::LastWins
+    }
+
     fn get_metadata(
         &self,
-        comment: Comment,
+        comment: &Comment,
         sequence_no: u32,
+        timestamp: DateTime<Utc>,
     ) -> MetadataUpdate;
     /*
     Get information about a user from a chat message
 
     :param: comment A comment from the user
+    :param: timestamp When the comment was sent
     :returns: A partial update to a user's metadata (dictionary of
               username to value)
     */
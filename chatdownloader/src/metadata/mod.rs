@@ -3,106 +3,139 @@ pub mod basic_info;
 pub mod metadatatrait;
 pub mod special_role;
 
+use futures::future::join_all;
 use futures::join;
-use log::debug;
-use log::warn;
+use log::{debug, info};
 use std::collections::HashMap;
-use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 
-use crate::_types::clptypes::MetadataTypes;
+use crate::_types::clptypes::{MergeRule, MetadataTypes};
 use crate::_types::clptypes::MetadataUpdate;
-use crate::_types::twitchtypes::Comment;
+use crate::config::Config;
+use crate::lookups::Lookups;
 use crate::metadata::metadatatrait::AbstractMetadata;
+use crate::pipeline::{CommentBatch, FanOut, BATCH_BUFFER};
 use crate::twitch_utils::TwitchAPIWrapper;
+
+/// Every piece of metadata, by name. They run in this order unless disabled in the config
+pub const METADATA_NAMES: [&str; 3] = ["basic_info", "badges", "special_role"];
+
+type BoxedMetadata = Box<dyn AbstractMetadata + Send + Sync>;
+
+/// Create the metadata registered under name, or None if there is no such metadata
+async fn create_metadata(
+    name: &str,
+    twitch: Option<&TwitchAPIWrapper>,
+    lookups: Option<&Lookups>,
+    channel_id: &str,
+) -> Option<BoxedMetadata> {
+    let metadata: BoxedMetadata = match name {
+        "basic_info" => Box::new(basic_info::BasicInfo::new(twitch, channel_id).await),
+        "badges" => Box::new(badges::Badges::from_lookups(lookups)),
+        "special_role" => Box::new(special_role::SpecialRole::new(twitch, channel_id).await),
+        _ => return None,
+    };
+    Some(metadata)
+}
+
 pub struct MetadataProcessor {
     pub defaults: HashMap<String, MetadataTypes>,
-    broadcast_receiver: broadcast::Receiver<(Comment, u32)>,
-    mpsc_sender: mpsc::Sender<MetadataUpdate>,
-    basic_info: basic_info::BasicInfo,
-    badges: badges::Badges,
-    special_role: special_role::SpecialRole,
+    pub merge_rules: HashMap<String, MergeRule>,
+    comment_receiver: mpsc::Receiver<CommentBatch>,
+    mpsc_sender: mpsc::Sender<Vec<MetadataUpdate>>,
+    metadata: Vec<BoxedMetadata>,
 }
 
 impl MetadataProcessor {
-    pub async fn new(twitch: &TwitchAPIWrapper, broadcast_receiver: broadcast::Receiver<(Comment, u32)>, mpsc_sender: mpsc::Sender<MetadataUpdate>) -> Self {
+    /// Create a new MetadataProcessor with every piece of metadata that is not disabled in the
+    /// config. Merge rules given in the config replace the ones the metadata declares. Badges
+    /// are resolved with the lookups, if there are any
+    pub async fn new(twitch: Option<&TwitchAPIWrapper>, lookups: Option<&Lookups>, channel_id: &str, config: &Config, comment_receiver: mpsc::Receiver<CommentBatch>, mpsc_sender: mpsc::Sender<Vec<MetadataUpdate>>) -> Self {
         let mut defaults: HashMap<String, MetadataTypes> = HashMap::new();
+        let mut merge_rules: HashMap<String, MergeRule> = HashMap::new();
+        let mut metadata: Vec<BoxedMetadata> = Vec::new();
 
-        // Initialize the metadata
-        let basic_info = basic_info::BasicInfo::new(twitch).await;
-        let badges = badges::Badges::new(twitch).await;
-        let special_role = special_role::SpecialRole::new(twitch).await;
+        for name in METADATA_NAMES {
+            if config.metadata.disabled.iter().any(|disabled| disabled == name) {
+                info!("Metadata {} is disabled", name);
+                continue;
+            }
+            let provider = create_metadata(name, twitch, lookups, channel_id)
+                .await
+                .expect("every name in METADATA_NAMES is registered");
+            let merge_rule = config
+                .metadata
+                .merge
+                .get(name)
+                .copied()
+                .unwrap_or_else(|| provider.get_merge_rule());
 
-        // Add names and default values to the metadata
-        defaults.insert(basic_info.get_name(), basic_info.get_default_value());
-        defaults.insert(badges.get_name(), badges.get_default_value());
-        defaults.insert(special_role.get_name(), special_role.get_default_value());
+            defaults.insert(provider.get_name(), provider.get_default_value());
+            merge_rules.insert(provider.get_name(), merge_rule);
+            metadata.push(provider);
+        }
 
         Self {
             defaults,
-            broadcast_receiver,
+            merge_rules,
+            comment_receiver,
             mpsc_sender,
-            basic_info,
-            badges,
-            special_role,
+            metadata,
         }
     }
 
-    pub async fn run(&mut self) {
-        join!(
-            calc_metadata(
-                &mut self.basic_info,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-            calc_metadata(
-                &mut self.badges,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-            calc_metadata(
-                &mut self.special_role,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-        );
+    /// Collect the metadata until the comments run out. Fails if any metadata stopped before
+    /// the end of the chat or could not hand over its results
+    pub async fn run(self) -> Result<(), String> {
+        let mut fan_out = FanOut::default();
+        let metadata_runs: Vec<_> = self
+            .metadata
+            .into_iter()
+            .map(|metadata| {
+                let receiver = fan_out.subscribe(format!("Metadata {}", metadata.get_name()));
+                calc_metadata(metadata, self.mpsc_sender.clone(), receiver)
+            })
+            .collect();
+        drop(self.mpsc_sender);
+
+        let (forwarded, results) = join!(fan_out.forward(self.comment_receiver), join_all(metadata_runs));
+        // The metadata's own errors explain why the comments could not be forwarded
+        results.into_iter().chain([forwarded]).collect::<Result<(), String>>()?;
         debug!("All metadata finished");
+        Ok(())
     }
 }
 
-async fn calc_metadata<M: AbstractMetadata + Send + Sync + 'static>(
-    metadata: &mut M,
-    sender: mpsc::Sender<MetadataUpdate>,
-    mut reciever: broadcast::Receiver<(Comment, u32)>,
-) {
+async fn calc_metadata(
+    metadata: BoxedMetadata,
+    sender: mpsc::Sender<Vec<MetadataUpdate>>,
+    mut reciever: mpsc::Receiver<CommentBatch>,
+) -> Result<(), String> {
     /*
-    Find metadata based on chat messages sent by a tokio broadcast channel
+    Find metadata for every batch of comments, sending the results of each batch together
     */
-    loop {
-        let (comment, sequence_no) = match reciever.recv().await {
-            Ok((comment, sequence_no)) => (comment, sequence_no),
-            Err(_) => break,
-        };
-        let metadata = (*metadata).get_metadata(comment, sequence_no);
-        if let Err(e) = sender.send(metadata).await {
-            warn!("Failed to send metadata result {}", e)
-        };
+    while let Some(batch) = reciever.recv().await {
+        let updates = batch
+            .iter()
+            .map(|(comment, sequence_no)| metadata.get_metadata(comment, *sequence_no, comment.created_at))
+            .collect();
+        sender
+            .send(updates)
+            .await
+            .map_err(|_| format!("Results of metadata {} were dropped", metadata.get_name()))?;
     }
+    Ok(())
 }
 
-#[allow(clippy::type_complexity)]
-/// Get the default values for the metrics and set up the channels
-pub async fn setup_metadata_and_channels(twitch: &TwitchAPIWrapper) -> (
-    MetadataProcessor,
-    broadcast::Sender<(Comment, u32)>,
-    mpsc::Receiver<MetadataUpdate>,
-) {
-    let (broadcast_sender, broadcast_receiver) = broadcast::channel(100000);
-    let (mpsc_sender, mpsc_receiver) = mpsc::channel(100000);
-    let metadata_processor = MetadataProcessor::new(twitch, broadcast_receiver, mpsc_sender).await;
-    (
-        metadata_processor,
-        broadcast_sender,
-        mpsc_receiver,
-    )
-}
\ No newline at end of file
+/// Get the default values for the metadata and set up the channel for their results
+pub async fn setup_metadata_and_channels(
+    twitch: Option<&TwitchAPIWrapper>,
+    lookups: Option<&Lookups>,
+    channel_id: &str,
+    config: &Config,
+    comment_receiver: mpsc::Receiver<CommentBatch>,
+) -> (MetadataProcessor, mpsc::Receiver<Vec<MetadataUpdate>>) {
+    let (mpsc_sender, mpsc_receiver) = mpsc::channel(BATCH_BUFFER);
+    let metadata_processor = MetadataProcessor::new(twitch, lookups, channel_id, config, comment_receiver, mpsc_sender).await;
+    (metadata_processor, mpsc_receiver)
+}
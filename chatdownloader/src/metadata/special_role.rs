@@ -2,9 +2,10 @@
 Figures out if the user is a special role
 */
 
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 
-use crate::_types::clptypes::{MetadataTypes, MetadataUpdate};
+use crate::_types::clptypes::{MergeRule, MetadataTypes, MetadataUpdate};
 use crate::_types::twitchtypes::Comment;
 use crate::metadata::metadatatrait::AbstractMetadata;
 use crate::twitch_utils::TwitchAPIWrapper;
@@ -19,7 +20,7 @@ impl AbstractMetadata for SpecialRole {
     Figures out if the user is a special role
     */
 
-    async fn new(_twitch: &TwitchAPIWrapper) -> Self {
+    async fn new(_twitch: Option<&TwitchAPIWrapper>, _channel_id: &str) -> Self {
         Self
     }
 
@@ -31,13 +32,19 @@ impl AbstractMetadata for SpecialRole {
         MetadataTypes::Bool(false)
     }
 
+    /// A user with a special role on any message keeps it, matching the badges they are shown with
+    fn get_merge_rule(&self) -> MergeRule {
+        MergeRule::Or
+    }
+
     fn get_metadata(
         &self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetadataUpdate {
         let mut metadata: HashMap<String, MetadataTypes> = HashMap::new();
-        let user_badges = comment.message.user_badges;
+        let user_badges = &comment.message.user_badges;
         if user_badges.is_none() {
             metadata.insert(comment.commenter._id.clone(), MetadataTypes::Bool(false));
             return MetadataUpdate {
@@ -45,7 +52,7 @@ impl AbstractMetadata for SpecialRole {
                 updates: metadata
             };
         }
-        let user_badges = user_badges.unwrap();
+        let user_badges = user_badges.as_ref().unwrap();
         for badge in user_badges {
             if SPECIAL_ROLES.contains(&badge._id.as_str()) {
                 metadata.insert(comment.commenter._id.clone(), MetadataTypes::Bool(true));
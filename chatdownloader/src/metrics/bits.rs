@@ -1,15 +1,20 @@
+use chrono::{DateTime, Utc};
+
 use crate::_types::clptypes::MetricUpdate;
 use crate::_types::twitchtypes::Comment;
+use crate::config::Config;
 use crate::metrics::metrictrait::AbstractMetric;
 
-const WEIGHT_BITS: f32 = 0.1;
-
 #[derive(Default, Debug)]
-pub struct Bits;
+pub struct Bits {
+    weight: f32,
+}
 
 impl AbstractMetric for Bits {
-    async fn new() -> Self {
-        Self
+    async fn new(_channel_id: &str, config: &Config) -> Self {
+        Self {
+            weight: config.metrics.bits.weight,
+        }
     }
 
     fn can_parallelize(&self) -> bool {
@@ -22,10 +27,11 @@ impl AbstractMetric for Bits {
 
     fn get_metric(
         &mut self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetricUpdate {
-        let score = comment.message.bits_spent as f32 * WEIGHT_BITS;
+        let score = comment.message.bits_spent as f32 * self.weight;
         self._shortcut_for_this_comment_user(comment, score)
     }
 }
@@ -1,22 +1,126 @@
+use chrono::{DateTime, Utc};
 use log::debug;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::_types::clptypes::MetricUpdate;
 use crate::_types::twitchtypes::Comment;
+use crate::config::{Config, CopypastaConfig};
 use crate::metrics::metrictrait::AbstractMetric;
+use crate::metrics::similarity::SimilarityIndex;
+
+#[derive(Serialize, Debug)]
+pub struct ChainParticipant {
+    pub id: String,
+    pub username: String,
+    /// How many messages of the chain the user sent
+    pub messages: u32,
+}
 
-const WEIGHT_COPYPASTA: f32 = 0.3;
-const CHAIN_GRACE: u32 = 10;
-const MATCHING_THRESHOLD: f32 = 0.6;
+#[derive(Serialize, Debug)]
+pub struct CopypastaChain {
+    /*
+    A copypasta chain, as exported in the copypasta report
+    */
+    pub origin_message_id: String,
+    pub origin_user_id: String,
+    pub origin_username: String,
+    /// The text of the message that started the chain
+    pub text: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    /// How many messages carried on the chain, including the first
+    pub length: u32,
+    /// How many users other than the originator carried on the chain
+    pub followers: u32,
+    /// Everyone who took part, with the most messages first
+    pub participants: Vec<ChainParticipant>,
+}
 
 #[derive(Default, Debug)]
 pub struct CopypastaLeader {
-    history: Vec<(u32, String, String, u32)>,
+    /// (sequence number, sequence number of the chain's first message, sent at)
+    history: Vec<(u32, u32, DateTime<Utc>)>,
+    /// The text of every message in history, by sequence number
+    index: SimilarityIndex<u32>,
+    /// Every chain that is still going or had more than one message, by the sequence number of its first message
+    chains: BTreeMap<u32, CopypastaChain>,
+    config: CopypastaConfig,
+}
+
+impl CopypastaLeader {
+    /// Whether a message in the history can still carry on a chain at the given message
+    fn in_window(
+        &self,
+        item: &(u32, u32, DateTime<Utc>),
+        sequence_no: u32,
+        timestamp: DateTime<Utc>,
+    ) -> bool {
+        match self.config.chain_grace_seconds {
+            Some(seconds) => (timestamp - item.2).num_milliseconds() as f32 / 1000.0 <= seconds,
+            None => (sequence_no - item.0) <= self.config.chain_grace,
+        }
+    }
+
+    /// Record a message in the chain started by the message with parent_sequence. Returns
+    /// true if it is the first message in the chain from someone other than its originator
+    fn add_to_chain(
+        &mut self,
+        parent_sequence: u32,
+        comment: &Comment,
+        text: String,
+        timestamp: DateTime<Utc>,
+    ) -> bool {
+        let chain = self.chains.entry(parent_sequence).or_insert_with(|| CopypastaChain {
+            origin_message_id: comment._id.clone(),
+            origin_user_id: comment.commenter._id.clone(),
+            origin_username: comment.commenter.display_name.clone(),
+            text,
+            started_at: timestamp,
+            ended_at: timestamp,
+            length: 0,
+            followers: 0,
+            participants: Vec::new(),
+        });
+        chain.length += 1;
+        chain.ended_at = timestamp;
+
+        let new_follower = match chain
+            .participants
+            .iter_mut()
+            .find(|participant| participant.id == comment.commenter._id)
+        {
+            Some(participant) => {
+                participant.messages += 1;
+                false
+            }
+            None => {
+                chain.participants.push(ChainParticipant {
+                    id: comment.commenter._id.clone(),
+                    username: comment.commenter.display_name.clone(),
+                    messages: 1,
+                });
+                chain.length > 1
+            }
+        };
+        if new_follower {
+            chain.followers += 1;
+        }
+        chain
+            .participants
+            .sort_by_key(|participant| Reverse(participant.messages));
+        new_follower
+    }
 }
 
 impl AbstractMetric for CopypastaLeader {
-    async fn new() -> Self {
+    async fn new(_channel_id: &str, config: &Config) -> Self {
         Self {
             history: Vec::new(),
+            index: SimilarityIndex::default(),
+            chains: BTreeMap::new(),
+            config: config.metrics.copypasta.clone(),
         }
     }
 
@@ -28,7 +132,7 @@ impl AbstractMetric for CopypastaLeader {
         String::from("copypasta")
     }
 
-    fn get_metric(&mut self, comment: Comment, sequence_no: u32) -> MetricUpdate {
+    fn get_metric(&mut self, comment: &Comment, sequence_no: u32, timestamp: DateTime<Utc>) -> MetricUpdate {
         let text = comment
             .message
             .fragments
@@ -41,43 +145,21 @@ impl AbstractMetric for CopypastaLeader {
             return MetricUpdate::default();
         }
 
-        // Evaluate or initialize the list
-        if self.history.is_empty() {
-            self.history.push((
-                sequence_no,
-                text.clone(),
-                comment.commenter._id.clone(),
-                sequence_no,
-            ));
-        }
-
         debug!("Size of heap: {}", self.history.len());
 
-        // Find the best matching string in the list
-        let (best_match, best_match_score) = self
-            .history
-            .iter()
-            .map(|item| {
-                let lcs = lcs(&text, &item.1);
-                let score = lcs.len() as f32 / text.len().max(item.1.len()) as f32;
-                (item, score)
-            })
-            .max_by(|(_, score1), (_, score2)| score1.partial_cmp(score2).unwrap())
-            .unwrap();
-
-        // If the best match is above the threshold, update the list
-        let parent_sequence = if best_match_score > MATCHING_THRESHOLD {
-            best_match.3
-        } else {
-            sequence_no
+        // Carry on the chain of the most similar recent message, or start a new one
+        let parent_sequence = match self.index.best_match(&text) {
+            Some((matched, score)) if score > self.config.matching_threshold => self
+                .history
+                .binary_search_by_key(&matched, |item| item.0)
+                .map(|position| self.history[position].1)
+                .unwrap_or(sequence_no),
+            _ => sequence_no,
         };
 
-        self.history.push((
-            sequence_no,
-            text.clone(),
-            comment.commenter._id.clone(),
-            parent_sequence,
-        ));
+        self.history.push((sequence_no, parent_sequence, timestamp));
+        self.index.insert(sequence_no, &text);
+        let new_follower = self.add_to_chain(parent_sequence, comment, text, timestamp);
 
         // Sort the list
         self.history.sort_by_key(|item| item.0);
@@ -96,82 +178,41 @@ impl AbstractMetric for CopypastaLeader {
         );
 
         // Evict old list top
-        let result = self
-            .history
-            .iter()
-            .take_while(|item| (sequence_no - item.0) <= CHAIN_GRACE)
-            .map(|item| (item.2.clone(), (item.0 - item.3) as f32 * WEIGHT_COPYPASTA))
-            .collect();
-
-        self.history = self
+        let evicted = self
             .history
             .iter()
-            .skip_while(|item| (sequence_no - item.0) > CHAIN_GRACE)
-            .cloned()
-            .collect();
-
-        MetricUpdate {
-            metric_name: self.get_name(),
-            updates: result,
+            .take_while(|item| !self.in_window(item, sequence_no, timestamp))
+            .count();
+        for item in self.history.drain(..evicted) {
+            self.index.remove(item.0);
+            // Nobody can carry on a chain that had a single message once that message is gone
+            if item.0 == item.1 && self.chains.get(&item.0).is_some_and(|chain| chain.length == 1) {
+                self.chains.remove(&item.0);
+            }
         }
-    }
 
-    fn finish(&self) -> MetricUpdate {
+        // The originator is credited for everyone who copies them, once per user. Nobody
+        // scores for repeating a message, whether their own or someone else's again
+        let mut updates = HashMap::new();
+        if new_follower {
+            let chain = &self.chains[&parent_sequence];
+            debug!("{} copied {}", comment.commenter._id, chain.origin_user_id);
+            updates.insert(chain.origin_user_id.clone(), self.config.weight);
+        }
         MetricUpdate {
             metric_name: self.get_name(),
-            updates: self.history
-                .iter()
-                .map(|item| (item.2.clone(), (item.0 - item.3) as f32 * WEIGHT_COPYPASTA))
-                .collect(),
+            updates,
         }
     }
-}
-
-fn lcs(s1: &str, s2: &str) -> String {
-    let mut max: Option<String> = None; // Holds value of string with maximum length
-    let mut current = String::new(); // String container to hold current longest value
-    let mut s1_iter = s1.chars().peekable(); // Peekable iterator for string s1
-    let mut s2_iter = s2.chars(); //Iterator for string s2
-    let mut s2_prev_pos = s2_iter.clone(); // Iterator that holds position of previous location of first iterator
-    let mut s1_prev_pos = s1_iter.clone(); // Peekable iterator used to make sure all possible combinations are located.
-
-    loop {
-        let s1_char = s1_iter.next(); // Get character in s1
-
-        if current.is_empty() {
-            // If no consecutive string found yet store location of iterator
-            s1_prev_pos = s1_iter.clone()
-        }
 
-        match s1_char {
-            Some(s1_char) => loop {
-                match s2_iter.next() {
-                    Some(s2_char) if s1_char == s2_char => {
-                        current.push(s1_char);
-                        s2_prev_pos = s2_iter.clone();
-                        break;
-                    }
-                    Some(_) => continue,
-                    None => {
-                        s2_iter = s2_prev_pos.clone();
-                        break;
-                    }
-                }
-            },
-            None => match s1_prev_pos.peek() {
-                Some(_) => {
-                    if max.as_ref().map_or(true, |s| s.len() < current.len()) {
-                        max = Some(current.clone());
-                    }
-                    current.clear();
-
-                    s1_iter = s1_prev_pos.clone();
-                    s2_iter = s2.chars();
-                }
-                None => break,
-            },
-        }
+    /// The chains with more than one message, longest first
+    fn get_report(&self) -> Option<serde_json::Value> {
+        let mut chains: Vec<&CopypastaChain> = self
+            .chains
+            .values()
+            .filter(|chain| chain.length > 1)
+            .collect();
+        chains.sort_by_key(|chain| Reverse(chain.length));
+        Some(serde_json::json!({ "chains": chains }))
     }
-
-    max.unwrap_or_default()
 }
@@ -4,36 +4,84 @@ The emote metric
 
 use std::collections::HashSet;
 
-use lazy_static::lazy_static;
+use chrono::{DateTime, Utc};
 use log::{debug, info};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::_constants::VED_CH_ID;
 use crate::_types::clptypes::MetricUpdate;
 use crate::_types::twitchtypes::{ChatMessageFragment, Comment};
+use crate::config::Config;
 
 use super::metrictrait::AbstractMetric;
 
-const WEIGHT_EMOTES: f32 = 0.02;
 
-lazy_static! {
-    static ref SEVEN_TV_URL: String = format!("https://7tv.io/v3/users/twitch/{}", VED_CH_ID);
+const SEVEN_TV_URL: &str = "https://7tv.io/v3/users/twitch";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SevenTVEmote {
+    pub name: String,
+    pub emote_url: String,
 }
 
-#[derive(Deserialize)]
-struct SevenTVEmote {
-    name: String,
-    #[allow(dead_code)]
-    emote_url: String,
+/// Get the channel's 7TV emotes, which are empty if 7TV cannot be reached
+pub async fn fetch_7tv_emotes(channel_id: &str) -> Vec<SevenTVEmote> {
+    info!("Getting the 7TV channel emotes for {}", channel_id);
+    let response = reqwest::get(format!("{}/{}", SEVEN_TV_URL, channel_id)).await;
+    if response.is_err() {
+        info!("Cannot get 7tv emotes");
+        return Vec::new();
+    }
+
+    let resp_body: serde_json::Value = response.unwrap().json().await.unwrap();
+    let mut ret_val = Vec::new();
+    if let Some(raw_emotes) = resp_body["emote_set"]["emotes"].as_array() {
+        for raw_emote in raw_emotes {
+            let host_url = raw_emote["data"]["host"]["url"].as_str().unwrap();
+            let filename = raw_emote["data"]["host"]["files"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|emote| emote["name"].as_str().unwrap().ends_with(".webp"))
+                .max_by_key(|emote| emote["width"].as_i64().unwrap())
+                .unwrap();
+            ret_val.push(SevenTVEmote {
+                name: raw_emote["name"].as_str().unwrap().to_owned(),
+                emote_url: format!("https://{}/{}", host_url, filename["name"]),
+            });
+        }
+    } else {
+        info!("Cannot access the required keys to get the emotes");
+    }
+
+    debug!("Got {} 7tv emotes", ret_val.len());
+    ret_val
 }
 
 pub struct Emote {
     #[allow(dead_code)]
     seventv_emotes: Vec<SevenTVEmote>,
     seventv_lookup: HashSet<String>,
+    weight: f32,
 }
 
 impl Emote {
+    /// An emote metric that only counts Twitch emotes, for when 7TV cannot be reached
+    pub fn without_7tv(config: &Config) -> Self {
+        Self::with_7tv_emotes(Vec::new(), config)
+    }
+
+    /// An emote metric that counts Twitch emotes and the given 7TV emotes, e.g. ones saved
+    /// by an earlier run
+    pub fn with_7tv_emotes(seventv_emotes: Vec<SevenTVEmote>, config: &Config) -> Self {
+        let seventv_lookup: HashSet<String> =
+            seventv_emotes.iter().map(|emote| emote.name.clone()).collect();
+        Self {
+            seventv_emotes,
+            seventv_lookup,
+            weight: config.metrics.emote.weight,
+        }
+    }
+
     fn count_7tv_emotes_in_fragment(&self, fragment: &ChatMessageFragment) -> usize {
         let mut count = 0;
         for word in fragment.text.split(' ') {
@@ -47,45 +95,8 @@ impl Emote {
 }
 
 impl AbstractMetric for Emote {
-    async fn new() -> Self {
-        info!("Getting the 7TV channel emotes");
-        let response = reqwest::get(SEVEN_TV_URL.clone()).await;
-        if response.is_err() {
-            info!("Cannot get 7tv emotes");
-            return Self {
-                seventv_emotes: Vec::new(),
-                seventv_lookup: HashSet::new(),
-            };
-        }
-
-        let resp_body: serde_json::Value = response.unwrap().json().await.unwrap();
-        let mut ret_val = Vec::new();
-        if let Some(raw_emotes) = resp_body["emote_set"]["emotes"].as_array() {
-            for raw_emote in raw_emotes {
-                let host_url = raw_emote["data"]["host"]["url"].as_str().unwrap();
-                let filename = raw_emote["data"]["host"]["files"]
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .filter(|emote| emote["name"].as_str().unwrap().ends_with(".webp"))
-                    .max_by_key(|emote| emote["width"].as_i64().unwrap())
-                    .unwrap();
-                ret_val.push(SevenTVEmote {
-                    name: raw_emote["name"].as_str().unwrap().to_owned(),
-                    emote_url: format!("https://{}/{}", host_url, filename["name"]),
-                });
-            }
-        } else {
-            info!("Cannot access the required keys to get the emotes");
-        }
-
-        debug!("Got {} 7tv emotes", ret_val.len());
-        let seventv_lookup: HashSet<String> =
-            ret_val.iter().map(|emote| emote.name.clone()).collect();
-        Self {
-            seventv_emotes: ret_val,
-            seventv_lookup,
-        }
+    async fn new(channel_id: &str, config: &Config) -> Self {
+        Self::with_7tv_emotes(fetch_7tv_emotes(channel_id).await, config)
     }
 
     fn can_parallelize(&self) -> bool {
@@ -98,8 +109,9 @@ impl AbstractMetric for Emote {
 
     fn get_metric(
         &mut self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetricUpdate {
         let score: f32 = comment
             .message
@@ -108,7 +120,7 @@ impl AbstractMetric for Emote {
             .map(|fragment| {
                 (fragment.emoticon.is_some() as u16 as f32
                     + self.count_7tv_emotes_in_fragment(fragment) as f32)
-                    * WEIGHT_EMOTES
+                    * self.weight
             })
             .sum();
         self._shortcut_for_this_comment_user(comment, score)
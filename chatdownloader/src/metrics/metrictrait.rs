@@ -1,21 +1,32 @@
+use chrono::{DateTime, Utc};
+
 use crate::_types::twitchtypes::Comment;
 use crate::_types::clptypes::MetricUpdate;
+use crate::config::Config;
 use std::collections::HashMap;
 
 pub trait AbstractMetric {
     /*
     Defines the trait for a metric
+
+    Apart from new, every method can be called on a boxed
+    dyn AbstractMetric, which is how MetricProcessor holds its metrics.
+    A new metric is registered in metrics::create_metric and
+    metrics::METRIC_NAMES
     */
-    async fn new() -> Self
+    async fn new(channel_id: &str, config: &Config) -> Self
     where
         Self: Sized;
     /*
     Initializes the metric
+
+    :param channel_id: The Twitch channel whose chat is being scored
+    :param config: The scoring configuration, with this metric's weights
     */
 
     fn _shortcut_for_this_comment_user(
         &self,
-        comment: Comment,
+        comment: &Comment,
         score: f32,
     ) -> MetricUpdate {
         // return {comment.commenter._id: score}
@@ -27,10 +38,13 @@ pub trait AbstractMetric {
         }
     }
 
-    #[allow(dead_code)]
     fn can_parallelize(&self) -> bool;
     /*
     Indicates to the executor if this metric can be parallelized
+
+    Such a metric must not depend on other users' comments: each
+    worker has its own instance and only sees the comments of some
+    users, though it sees each of them in order
     */
 
     fn get_name(&self) -> String;
@@ -38,17 +52,29 @@ pub trait AbstractMetric {
     Returns the name of the metric
     */
 
-    fn get_metric(&mut self, comment: Comment, sequence_no: u32) -> MetricUpdate;
+    fn get_metric(&mut self, comment: &Comment, sequence_no: u32, timestamp: DateTime<Utc>) -> MetricUpdate;
     /*
     Gets the score for a particular comment
 
     :param comment: The comment to process
     :param sequence_no: The sequence number of the comment
+    :param timestamp: When the comment was sent. The offset into
+                      the stream is comment.content_offset_seconds
     :return: A HashMap. The HashMap contains the user id and
              the score to add for the user involved in this
              metric.
     */
 
+    fn get_report(&self) -> Option<serde_json::Value> {
+        /*
+        A structured report about the whole stream, saved next to the
+        leaderboards as reports/<stream>.<metric name>.json. It is asked
+        for once, after finish. Metrics that can be parallelized are not
+        asked for one, as each worker only sees some of the users
+        */
+        None
+    }
+
     fn finish(&self) -> MetricUpdate {
         /*
         This method is called when there are no more comments to process.
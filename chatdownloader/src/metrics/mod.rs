@@ -2,131 +2,227 @@ pub mod bits;
 pub mod copypastaleader;
 pub mod emote;
 pub mod metrictrait;
+pub mod similarity;
 pub mod subs;
 pub mod text;
 
+use futures::future::join_all;
 use futures::join;
-use log::debug;
-use log::warn;
+use log::{debug, info};
 use std::collections::HashMap;
-use tokio::sync::broadcast;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use tokio::sync::mpsc;
 
-use crate::_types::clptypes::MetricUpdate;
+use crate::_types::clptypes::{MetricReport, MetricUpdate};
 use crate::_types::twitchtypes::Comment;
+use crate::config::Config;
+use crate::lookups::Lookups;
 use crate::metrics::metrictrait::AbstractMetric;
+use crate::pipeline::{CommentBatch, FanOut, BATCH_BUFFER};
+
+/// Every metric, by name. Metrics run in this order unless disabled in the config
+pub const METRIC_NAMES: [&str; 5] = ["bits", "subs", "text", "copypasta", "emote"];
+
+type BoxedMetric = Box<dyn AbstractMetric + Send + Sync>;
+
+/// Create the metric registered under name, or None if there is no such metric
+async fn create_metric(
+    name: &str,
+    lookups: Option<&Lookups>,
+    channel_id: &str,
+    config: &Config,
+) -> Option<BoxedMetric> {
+    let metric: BoxedMetric = match name {
+        "bits" => Box::new(bits::Bits::new(channel_id, config).await),
+        "subs" => Box::new(subs::Subs::new(channel_id, config).await),
+        "text" => Box::new(text::Text::new(channel_id, config).await),
+        "copypasta" => Box::new(copypastaleader::CopypastaLeader::new(channel_id, config).await),
+        "emote" => Box::new(match lookups {
+            Some(lookups) => emote::Emote::with_7tv_emotes(lookups.seventv_emotes.clone(), config),
+            None => emote::Emote::without_7tv(config),
+        }),
+        _ => return None,
+    };
+    Some(metric)
+}
 
 pub struct MetricProcessor {
+    /*
+    Runs the enabled metrics over the comments.
+
+    Metrics that can be parallelized are run by several workers on the
+    tokio thread pool, each with its own instance of those metrics.
+    Comments are sharded between the workers by user, so each user's
+    updates still arrive in comment order and sum to the same score
+    on every run. The other metrics see every comment in order
+    */
     pub defaults: HashMap<String, f32>,
-    broadcast_receiver: broadcast::Receiver<(Comment, u32)>,
-    mpsc_sender: mpsc::Sender<MetricUpdate>,
-    bits: bits::Bits,
-    subs: subs::Subs,
-    text: text::Text,
-    copypastaleader: copypastaleader::CopypastaLeader,
-    emote: emote::Emote,
+    comment_receiver: mpsc::Receiver<CommentBatch>,
+    mpsc_sender: mpsc::Sender<Vec<MetricUpdate>>,
+    sequential_metrics: Vec<BoxedMetric>,
+    parallel_workers: Vec<Vec<BoxedMetric>>,
 }
 
 impl MetricProcessor {
-    /// Create a new MetricProcessor
-    /// get_defaults_and_setup_channels must be called before run
-    pub async fn new(broadcast_receiver: broadcast::Receiver<(Comment, u32)>, mpsc_sender: mpsc::Sender<MetricUpdate>) -> Self {
+    /// Create a new MetricProcessor with every metric that is not disabled in the config
+    /// Without lookups, metrics that need them start without, e.g. 7TV emotes are not counted
+    pub async fn new(lookups: Option<&Lookups>, channel_id: &str, config: &Config, comment_receiver: mpsc::Receiver<CommentBatch>, mpsc_sender: mpsc::Sender<Vec<MetricUpdate>>) -> Self {
         let mut defaults: HashMap<String, f32> = HashMap::new();
+        let mut sequential_metrics: Vec<BoxedMetric> = Vec::new();
+        let worker_count = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut parallel_workers: Vec<Vec<BoxedMetric>> = (0..worker_count).map(|_| Vec::new()).collect();
 
-        let bits = bits::Bits::new().await;
-        let subs = subs::Subs::new().await;
-        let text = text::Text::new().await;
-        let copypastaleader = copypastaleader::CopypastaLeader::new().await;
-        let emote = emote::Emote::new().await;
+        for name in METRIC_NAMES {
+            if config.metrics.disabled.iter().any(|disabled| disabled == name) {
+                info!("Metric {} is disabled", name);
+                continue;
+            }
+            let metric = create_metric(name, lookups, channel_id, config)
+                .await
+                .expect("every name in METRIC_NAMES is registered");
+            defaults.insert(metric.get_name(), 0.0);
 
-        defaults.insert(bits.get_name(), 0.0);
-        defaults.insert(subs.get_name(), 0.0);
-        defaults.insert(text.get_name(), 0.0);
-        defaults.insert(copypastaleader.get_name(), 0.0);
-        defaults.insert(emote.get_name(), 0.0);
+            if !metric.can_parallelize() || worker_count == 1 {
+                sequential_metrics.push(metric);
+                continue;
+            }
+            debug!("Running metric {} on {} workers", name, worker_count);
+            parallel_workers[0].push(metric);
+            for worker in parallel_workers.iter_mut().skip(1) {
+                worker.push(create_metric(name, lookups, channel_id, config).await.unwrap());
+            }
+        }
+        parallel_workers.retain(|worker| !worker.is_empty());
 
         Self {
             defaults,
-            broadcast_receiver,
+            comment_receiver,
             mpsc_sender,
-            bits,
-            subs,
-            text,
-            copypastaleader,
-            emote,
+            sequential_metrics,
+            parallel_workers,
         }
     }
 
-    pub async fn run(&mut self) {
-        join!(
-            calc_metric(
-                &mut self.bits,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-            calc_metric(
-                &mut self.subs,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-            calc_metric(
-                &mut self.text,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-            calc_metric(
-                &mut self.copypastaleader,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
-            calc_metric(
-                &mut self.emote,
-                self.mpsc_sender.clone(),
-                self.broadcast_receiver.resubscribe(),
-            ),
+    /// Run the metrics until the comments run out, returning the reports they made. Fails if
+    /// any metric stopped before the end of the chat or could not hand over its results
+    pub async fn run(self) -> Result<Vec<MetricReport>, String> {
+        let mut fan_out = FanOut::default();
+        let sequential_runs: Vec<_> = self
+            .sequential_metrics
+            .into_iter()
+            .map(|metric| {
+                let receiver = fan_out.subscribe(format!("Metric {}", metric.get_name()));
+                calc_metric(metric, self.mpsc_sender.clone(), receiver)
+            })
+            .collect();
+
+        let shards = self.parallel_workers.len();
+        let worker_runs: Vec<_> = self
+            .parallel_workers
+            .into_iter()
+            .enumerate()
+            .map(|(shard, metrics)| {
+                let receiver = fan_out.subscribe(format!("Metric worker {}", shard));
+                tokio::spawn(calc_metrics_worker(
+                    metrics,
+                    shard,
+                    shards,
+                    self.mpsc_sender.clone(),
+                    receiver,
+                ))
+            })
+            .collect();
+        drop(self.mpsc_sender);
+
+        let (forwarded, sequential_results, worker_results) = join!(
+            fan_out.forward(self.comment_receiver),
+            join_all(sequential_runs),
+            join_all(worker_runs),
         );
+        let worker_results = worker_results.into_iter().map(|result| {
+            result
+                .map_err(|e| format!("Metric worker failed: {}", e))
+                .and_then(|result| result)
+        });
+        // The metrics' own errors explain why the comments could not be forwarded
+        let reports: Vec<Option<MetricReport>> =
+            sequential_results.into_iter().collect::<Result<_, String>>()?;
+        worker_results.chain([forwarded]).collect::<Result<(), String>>()?;
         debug!("All metrics finished");
+        Ok(reports.into_iter().flatten().collect())
     }
 }
 
-async fn calc_metric<M: AbstractMetric + Sync + Send + 'static>(
-    metric: &mut M,
-    sender: mpsc::Sender<MetricUpdate>,
-    mut reciever: broadcast::Receiver<(Comment, u32)>,
-) {
+/// Which of shards workers scores the comment. All of a user's comments go to the same one
+fn shard_of(comment: &Comment, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    comment.commenter._id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+async fn calc_metrics_worker(
+    mut metrics: Vec<BoxedMetric>,
+    shard: usize,
+    shards: usize,
+    sender: mpsc::Sender<Vec<MetricUpdate>>,
+    mut reciever: mpsc::Receiver<CommentBatch>,
+) -> Result<(), String> {
     /*
-    Calculate the metric based on chat messages sent by a tokio broadcast channel
+    Calculate parallelizable metrics for the comments of the users in this worker's shard
     */
-    loop {
-        let (comment, sequence_no) = match reciever.recv().await {
-            Ok((comment, sequence_no)) => (comment, sequence_no),
-            Err(_) => break,
-        };
-        let metric_result = (*metric).get_metric(comment, sequence_no);
-        if let Err(e) = sender.send(metric_result).await {
-            warn!("Failed to send metric result: {}", e)
-        };
-        tokio::task::yield_now().await;
+    while let Some(batch) = reciever.recv().await {
+        let mut updates = Vec::new();
+        for (comment, sequence_no) in batch.iter().filter(|(comment, _)| shard_of(comment, shards) == shard) {
+            updates.extend(metrics.iter_mut().map(|metric| metric.get_metric(comment, *sequence_no, comment.created_at)));
+        }
+        sender
+            .send(updates)
+            .await
+            .map_err(|_| format!("Results of metric worker {} were dropped", shard))?;
     }
-    let metric_result = metric.finish();
-    if let Err(e) = sender.send(metric_result).await {
-        warn!("Failed to send final metric result: {}", e)
-    };
+    let final_updates = metrics.iter().map(|metric| metric.finish()).collect();
+    sender
+        .send(final_updates)
+        .await
+        .map_err(|_| format!("Final results of metric worker {} were dropped", shard))
+}
+
+async fn calc_metric(
+    mut metric: BoxedMetric,
+    sender: mpsc::Sender<Vec<MetricUpdate>>,
+    mut reciever: mpsc::Receiver<CommentBatch>,
+) -> Result<Option<MetricReport>, String> {
+    /*
+    Calculate the metric for every batch of comments, sending the results of each batch together
+    */
+    while let Some(batch) = reciever.recv().await {
+        let updates = batch
+            .iter()
+            .map(|(comment, sequence_no)| metric.get_metric(comment, *sequence_no, comment.created_at))
+            .collect();
+        sender
+            .send(updates)
+            .await
+            .map_err(|_| format!("Results of metric {} were dropped", metric.get_name()))?;
+    }
+    sender
+        .send(vec![metric.finish()])
+        .await
+        .map_err(|_| format!("Final results of metric {} were dropped", metric.get_name()))?;
+    Ok(metric.get_report().map(|report| MetricReport {
+        metric_name: metric.get_name(),
+        report,
+    }))
 }
 
-#[allow(clippy::type_complexity)]
-/// Get the default values for the metrics and set up the channels
-pub async fn setup_metrics_and_channels() -> (
-    MetricProcessor,
-    broadcast::Sender<(Comment, u32)>,
-    mpsc::Receiver<MetricUpdate>,
-) {
-    let (broadcast_sender, broadcast_receiver) = broadcast::channel(100000);
-    let (mpsc_sender, mpsc_receiver) = mpsc::channel(100000);
-    let metric_processor = MetricProcessor::new(broadcast_receiver, mpsc_sender).await;
-    (
-        metric_processor,
-        broadcast_sender,
-        mpsc_receiver,
-    )
-}
\ No newline at end of file
+/// Get the default values for the metrics and set up the channel for their results
+pub async fn setup_metrics_and_channels(
+    lookups: Option<&Lookups>,
+    channel_id: &str,
+    config: &Config,
+    comment_receiver: mpsc::Receiver<CommentBatch>,
+) -> (MetricProcessor, mpsc::Receiver<Vec<MetricUpdate>>) {
+    let (mpsc_sender, mpsc_receiver) = mpsc::channel(BATCH_BUFFER);
+    let metric_processor = MetricProcessor::new(lookups, channel_id, config, comment_receiver, mpsc_sender).await;
+    (metric_processor, mpsc_receiver)
+}
@@ -0,0 +1,300 @@
+/*
+Measures how similar chat messages are, for matching copypastas.
+
+Messages are compared as sets of character trigrams with their Jaccard
+similarity, from 0 (nothing in common) to 1 (the same trigrams). Text is
+split by char, so emoji and other non-ASCII text count as one character
+each rather than by their UTF-8 size.
+
+SimilarityIndex keeps a MinHash signature of every distinct text it
+holds, split into bands. Texts sharing a band are likely to be similar,
+so only those are compared exactly, instead of every message held.
+
+Shingles are hashed with a fixed function rather than std's DefaultHasher,
+whose output may change between Rust releases, so the same chat always
+matches the same copypastas. This module only uses std so the benchmarks
+can include it
+*/
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+
+/// Characters per shingle. Shorter messages are a single shingle
+const SHINGLE_CHARS: usize = 3;
+/// Two messages are compared if all rows of any band match. With 16 bands of 2 rows, messages
+/// with a similarity of 0.6 are compared with a probability of 99.9%, and of 0.3 with 78%
+const BANDS: usize = 16;
+const ROWS_PER_BAND: usize = 2;
+const SIGNATURE_LEN: usize = BANDS * ROWS_PER_BAND;
+/// Multiplier of FxHash, the hash rustc uses
+const FX_K: u64 = 0x517c_c1b7_2722_0a95;
+/// Seed of the shingle hash. Changing it changes which messages are compared
+const SHINGLE_SEED: u64 = 0x6368_6174_646c_6472;
+
+/// Lowercase the text and collapse whitespace, so these differences do not matter
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// The hashed character trigrams of a message, ignoring case and repeated whitespace
+#[allow(dead_code)] // Used by benches/similarity.rs
+pub fn shingles(text: &str) -> HashSet<u64> {
+    normalized_shingles(&normalize(text))
+}
+
+fn normalized_shingles(normalized: &str) -> HashSet<u64> {
+    let chars: Vec<char> = normalized.chars().collect();
+    if chars.is_empty() {
+        return HashSet::new();
+    }
+    chars
+        .windows(SHINGLE_CHARS.min(chars.len()))
+        .map(hash_shingle)
+        .collect()
+}
+
+/// FxHash of the characters, seeded and finished with mix so similar shingles get unrelated hashes
+fn hash_shingle(shingle: &[char]) -> u64 {
+    let hash = shingle.iter().fold(SHINGLE_SEED, |hash, c| {
+        (hash.rotate_left(5) ^ *c as u64).wrapping_mul(FX_K)
+    });
+    mix(hash)
+}
+
+/// The Jaccard similarity of two sets of shingles, from 0 to 1
+pub fn jaccard(a: &HashSet<u64>, b: &HashSet<u64>) -> f32 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let intersection = a.intersection(b).count();
+    intersection as f32 / (a.len() + b.len() - intersection) as f32
+}
+
+/// SplitMix64, used to derive the MinHash functions from the shingle hashes
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// The MinHash signature, using a*x + b with a different odd a and b for every slot
+fn signature(shingles: &HashSet<u64>) -> [u64; SIGNATURE_LEN] {
+    let mut hash_functions = [(0, 0); SIGNATURE_LEN];
+    for (i, function) in hash_functions.iter_mut().enumerate() {
+        *function = (mix(2 * i as u64) | 1, mix(2 * i as u64 + 1));
+    }
+
+    let mut signature = [u64::MAX; SIGNATURE_LEN];
+    for shingle in shingles {
+        let x = mix(*shingle);
+        for (min, (a, b)) in signature.iter_mut().zip(hash_functions.iter()) {
+            *min = (*min).min(x.wrapping_mul(*a).wrapping_add(*b));
+        }
+    }
+    signature
+}
+
+fn band_keys(signature: &[u64; SIGNATURE_LEN]) -> impl Iterator<Item = (usize, u64)> + '_ {
+    signature
+        .chunks(ROWS_PER_BAND)
+        .enumerate()
+        .map(|(band, rows)| (band, rows.iter().fold(0, |acc, row| mix(acc ^ row))))
+}
+
+#[derive(Debug)]
+struct Document<K> {
+    /*
+    A distinct normalized text, and the keys of the messages with it.
+    Spam repeats the same text a lot, so it is only compared once
+    */
+    text: String,
+    shingles: HashSet<u64>,
+    signature: [u64; SIGNATURE_LEN],
+    keys: BTreeSet<K>,
+}
+
+#[derive(Debug)]
+pub struct SimilarityIndex<K> {
+    /*
+    Messages by key, e.g. their sequence number, that can be searched for
+    the one most similar to a new message
+    */
+    documents: HashMap<u64, Document<K>>,
+    document_ids: HashMap<String, u64>,
+    keys: HashMap<K, u64>,
+    buckets: HashMap<(usize, u64), HashSet<u64>>,
+    next_document_id: u64,
+}
+
+impl<K> Default for SimilarityIndex<K> {
+    fn default() -> Self {
+        Self {
+            documents: HashMap::new(),
+            document_ids: HashMap::new(),
+            keys: HashMap::new(),
+            buckets: HashMap::new(),
+            next_document_id: 0,
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash + Ord> SimilarityIndex<K> {
+    /// Add a message, replacing any message with the same key
+    pub fn insert(&mut self, key: K, text: &str) {
+        self.remove(key);
+        let text = normalize(text);
+        let id = match self.document_ids.get(&text) {
+            Some(id) => *id,
+            None => {
+                let id = self.next_document_id;
+                self.next_document_id += 1;
+                let shingles = normalized_shingles(&text);
+                let signature = signature(&shingles);
+                for band in band_keys(&signature) {
+                    self.buckets.entry(band).or_default().insert(id);
+                }
+                self.document_ids.insert(text.clone(), id);
+                self.documents.insert(
+                    id,
+                    Document {
+                        text,
+                        shingles,
+                        signature,
+                        keys: BTreeSet::new(),
+                    },
+                );
+                id
+            }
+        };
+        self.documents.get_mut(&id).unwrap().keys.insert(key);
+        self.keys.insert(key, id);
+    }
+
+    pub fn remove(&mut self, key: K) {
+        let Some(id) = self.keys.remove(&key) else {
+            return;
+        };
+        let document = self.documents.get_mut(&id).unwrap();
+        document.keys.remove(&key);
+        if !document.keys.is_empty() {
+            return;
+        }
+
+        let document = self.documents.remove(&id).unwrap();
+        self.document_ids.remove(&document.text);
+        for band in band_keys(&document.signature) {
+            if let Some(bucket) = self.buckets.get_mut(&band) {
+                bucket.remove(&id);
+                if bucket.is_empty() {
+                    self.buckets.remove(&band);
+                }
+            }
+        }
+    }
+
+    /// The most similar message held and its similarity, if any message is likely to be
+    /// similar at all. Ties go to the message with the highest key, i.e. the latest
+    pub fn best_match(&self, text: &str) -> Option<(K, f32)> {
+        let text = normalize(text);
+        if let Some(id) = self.document_ids.get(&text) {
+            return Some((*self.documents[id].keys.last().unwrap(), 1.0));
+        }
+
+        let shingles = normalized_shingles(&text);
+        let signature = signature(&shingles);
+        let candidates: HashSet<u64> = band_keys(&signature)
+            .filter_map(|band| self.buckets.get(&band))
+            .flatten()
+            .copied()
+            .collect();
+
+        candidates
+            .into_iter()
+            .map(|id| {
+                let document = &self.documents[&id];
+                (
+                    *document.keys.last().unwrap(),
+                    jaccard(&shingles, &document.shingles),
+                )
+            })
+            .max_by(|(key1, score1), (key2, score2)| {
+                score1.total_cmp(score2).then_with(|| key1.cmp(key2))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chat message of words picked by seed from a small vocabulary
+    fn message(seed: u64, words: usize) -> Vec<&'static str> {
+        const VOCABULARY: [&str; 16] = [
+            "chat", "stream", "pog", "clip", "that", "insane", "play", "lets", "go", "no", "way",
+            "he", "actually", "did", "it", "again",
+        ];
+        (0..words)
+            .map(|i| VOCABULARY[(mix(seed * 64 + i as u64) % 16) as usize])
+            .collect()
+    }
+
+    #[test]
+    fn shingle_hashes_are_fixed() {
+        // If this changes, saved copypasta leaderboards can no longer be reproduced
+        assert_eq!(hash_shingle(&['p', 'o', 'g']), 0xa01c_ebf7_84ba_1f8d);
+        assert_eq!(shingles("pog").into_iter().collect::<Vec<_>>(), [0xa01c_ebf7_84ba_1f8d]);
+    }
+
+    #[test]
+    fn jaccard_of_trigrams() {
+        assert_eq!(jaccard(&shingles("hello world"), &shingles("  HELLO   world ")), 1.0);
+        // {abc, bcd} and {abc, bce} share one of three trigrams
+        assert_eq!(jaccard(&shingles("abcd"), &shingles("abce")), 1.0 / 3.0);
+        assert_eq!(jaccard(&shingles("abc"), &shingles("xyz")), 0.0);
+        assert_eq!(jaccard(&shingles(""), &shingles("")), 1.0);
+        // Emoji count as one character
+        assert_eq!(shingles("a😀b").len(), 1);
+    }
+
+    #[test]
+    fn edited_copypastas_stay_above_the_matching_threshold() {
+        let copypasta = "I'm not even mad, that's amazing. Chat, clip that before the mods see it";
+        let edited = "im not even mad thats amazing. chat clip that before the mods see it!!";
+        let unrelated = "what game is this? first time watching the stream, looks fun";
+        assert!(jaccard(&shingles(copypasta), &shingles(edited)) >= 0.6);
+        assert!(jaccard(&shingles(copypasta), &shingles(unrelated)) < 0.3);
+    }
+
+    #[test]
+    fn finds_similar_messages_through_the_bands() {
+        let mut index = SimilarityIndex::default();
+        let originals: Vec<String> = (0..500).map(|seed| message(seed, 12).join(" ")).collect();
+        for (key, text) in originals.iter().enumerate() {
+            index.insert(key, text);
+        }
+
+        // Change the last word of every message and look the edited ones up
+        let mut similar = 0;
+        let mut found = 0;
+        for (key, text) in originals.iter().enumerate() {
+            let mut words = message(key as u64, 12);
+            words[11] = "kappa";
+            let edited = words.join(" ");
+            if jaccard(&shingles(text), &shingles(&edited)) < 0.6 {
+                continue;
+            }
+            similar += 1;
+            let (_, score) = index.best_match(&edited).unwrap_or((0, 0.0));
+            if score >= jaccard(&shingles(text), &shingles(&edited)) {
+                found += 1;
+            }
+        }
+        // With 16 bands of 2 rows, a similarity of 0.6 is missed 0.1% of the time
+        assert!(similar > 400, "only {} edited messages stayed similar", similar);
+        assert!(found as f32 >= similar as f32 * 0.99, "found {} of {}", found, similar);
+    }
+}
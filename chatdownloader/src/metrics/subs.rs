@@ -1,15 +1,15 @@
 /*
 The subs metric
 */
+use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
 use regex::Regex;
 
 use crate::_types::clptypes::MetricUpdate;
 use crate::_types::twitchtypes::{ChatMessageFragment, Comment};
+use crate::config::Config;
 use crate::metrics::metrictrait::AbstractMetric;
 
-const WEIGHT_SUBS: f32 = 0.1;
-
 lazy_static! {
     static ref GIFTED_SUB_REGEX_1: Regex = Regex::new(
         r"(?P<gifter>[a-zA-Z0-9_]+) gifted a Tier (?P<tier>[0-9]) Sub to (?P<receiver>[a-zA-Z0-9_]+)!"
@@ -20,11 +20,15 @@ lazy_static! {
 }
 
 #[derive(Default, Debug)]
-pub struct Subs;
+pub struct Subs {
+    weight: f32,
+}
 
 impl AbstractMetric for Subs {
-    async fn new() -> Self {
-        Self
+    async fn new(_channel_id: &str, config: &Config) -> Self {
+        Self {
+            weight: config.metrics.subs.weight,
+        }
     }
 
     fn can_parallelize(&self) -> bool {
@@ -37,8 +41,9 @@ impl AbstractMetric for Subs {
 
     fn get_metric(
         &mut self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetricUpdate {
         let total_subs: i32 = comment
             .message
@@ -47,7 +52,7 @@ impl AbstractMetric for Subs {
             .map(no_of_gifted_subs)
             .sum();
 
-        let score = total_subs as f32 * WEIGHT_SUBS;
+        let score = total_subs as f32 * self.weight;
         self._shortcut_for_this_comment_user(comment, score)
     }
 }
@@ -1,22 +1,27 @@
+use chrono::{DateTime, Utc};
+
 use crate::_types::clptypes::MetricUpdate;
 /*
 The text metric
 */
 use crate::_types::twitchtypes::Comment;
+use crate::config::Config;
 use crate::metrics::metrictrait::AbstractMetric;
 
-const WEIGHT_TEXT: f32 = 0.02;
-
 #[derive(Default, Debug)]
-pub struct Text;
+pub struct Text {
+    weight: f32,
+}
 
 impl AbstractMetric for Text {
-    async fn new() -> Self {
-        Self
+    async fn new(_channel_id: &str, config: &Config) -> Self {
+        Self {
+            weight: config.metrics.text.weight,
+        }
     }
 
     fn can_parallelize(&self) -> bool {
-        false
+        true
     }
 
     fn get_name(&self) -> String {
@@ -25,14 +30,15 @@ impl AbstractMetric for Text {
 
     fn get_metric(
         &mut self,
-        comment: Comment,
+        comment: &Comment,
         _sequence_no: u32,
+        _timestamp: DateTime<Utc>,
     ) -> MetricUpdate {
-        let score = f32::max(0.0, calculate_score(comment.message.body.len()));
+        let score = f32::max(0.0, calculate_score(self.weight, comment.message.body.len()));
         self._shortcut_for_this_comment_user(comment, score)
     }
 }
 
-fn calculate_score(x: usize) -> f32 {
-    -WEIGHT_TEXT * x as f32 * (x as f32 - 20.0)
+fn calculate_score(weight: f32, x: usize) -> f32 {
+    -weight * x as f32 * (x as f32 - 20.0)
 }
@@ -0,0 +1,96 @@
+/*
+Carries comments from the chat source to the metrics and metadata.
+
+Comments travel in batches that every consumer shares through an Arc,
+so a comment is never copied per consumer. Every channel is bounded: a
+slow consumer makes the stages before it wait instead of falling behind
+and missing comments. A consumer that stops before the end of the chat
+is an error, as the comments it misses would silently lower scores
+*/
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::debug;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::_types::twitchtypes::Comment;
+
+/// Comments with their sequence numbers, in the order they were sent
+pub type CommentBatch = Arc<Vec<(Comment, u32)>>;
+
+/// The most comments sent in one batch
+const BATCH_SIZE: usize = 256;
+/// How many batches, or batches of results, may wait for a consumer before the sender waits
+pub const BATCH_BUFFER: usize = 16;
+
+pub struct FanOut<T = CommentBatch> {
+    /*
+    Sends every item, usually a batch of comments, to each of its consumers, in order
+    */
+    consumers: Vec<(String, mpsc::Sender<T>)>,
+}
+
+impl<T> Default for FanOut<T> {
+    fn default() -> Self {
+        Self { consumers: Vec::new() }
+    }
+}
+
+impl<T: Clone> FanOut<T> {
+    /// Add a consumer, named in errors, that receives every item sent from now on
+    pub fn subscribe(&mut self, name: impl Into<String>) -> mpsc::Receiver<T> {
+        let (sender, receiver) = mpsc::channel(BATCH_BUFFER);
+        self.consumers.push((name.into(), sender));
+        receiver
+    }
+
+    /// Send an item to every consumer, waiting until each has room for it
+    pub async fn send(&self, item: T) -> Result<(), String> {
+        for (name, sender) in self.consumers.iter() {
+            sender
+                .send(item.clone())
+                .await
+                .map_err(|_| format!("{} stopped before the end of the chat", name))?;
+        }
+        Ok(())
+    }
+
+    /// Send every item from receiver to the consumers, until receiver is closed
+    pub async fn forward(self, mut receiver: mpsc::Receiver<T>) -> Result<(), String> {
+        while let Some(item) = receiver.recv().await {
+            self.send(item).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Number the comments and send them to the consumers in batches. A batch is sent as soon
+/// as no more comments are waiting, so live chat is not held back to fill one
+/// Returns how many comments were sent, and when the stream started according to the first
+/// comment's timestamp and offset into the stream
+pub async fn batch_comments(
+    mut comment_receiver: mpsc::Receiver<Comment>,
+    fan_out: FanOut,
+) -> Result<(u32, Option<DateTime<Utc>>), String> {
+    let mut sequence_no: u32 = 0;
+    let mut started_at = None;
+    while let Some(comment) = comment_receiver.recv().await {
+        if started_at.is_none() {
+            let offset = TimeDelta::milliseconds((comment.content_offset_seconds * 1000.0) as i64);
+            started_at = Some(comment.created_at - offset);
+        }
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        batch.push((comment, sequence_no));
+        sequence_no += 1;
+        while batch.len() < BATCH_SIZE {
+            let Ok(comment) = comment_receiver.try_recv() else {
+                break;
+            };
+            batch.push((comment, sequence_no));
+            sequence_no += 1;
+        }
+        fan_out.send(Arc::new(batch)).await?;
+    }
+    debug!("Finished sending {} comments", sequence_no);
+    Ok((sequence_no, started_at))
+}
@@ -0,0 +1,132 @@
+/*
+Seasons of a channel's leaderboards.
+
+Each season's leaderboards are kept in <output dir>/seasons/<season>/,
+so starting a season starts everyone from the initial ELO again while
+the past seasons stay available for the all-time leaderboards. Closing
+a season records a closed.json in its directory, after which it is no
+longer written to
+*/
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::_types::clptypes::Channel;
+use crate::config::Config;
+use crate::leaderboards::{save_all_time, Transaction};
+
+const SEASONS_DIR: &str = "seasons";
+const CLOSED_RECORD: &str = "closed.json";
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ClosedSeason {
+    /*
+    Recorded in a season's directory when it is closed
+    */
+    pub season: String,
+    /// The season that was started in its place
+    pub next: String,
+    pub closed_at: DateTime<Utc>,
+}
+
+/// The directory of a season's leaderboards
+pub fn season_dir(output_dir: &Path, season: &str) -> PathBuf {
+    output_dir.join(SEASONS_DIR).join(season)
+}
+
+/// Every season with a directory in output_dir, oldest first
+pub fn list_seasons(output_dir: &Path) -> io::Result<Vec<String>> {
+    let seasons_dir = output_dir.join(SEASONS_DIR);
+    if !seasons_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut seasons = Vec::new();
+    for entry in fs::read_dir(seasons_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            seasons.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    seasons.sort_by(|a, b| compare_seasons(a, b));
+    Ok(seasons)
+}
+
+/// Order seasons by their dot separated parts, numerically where both parts are numbers,
+/// so 2.10 comes after 2.9
+fn compare_seasons(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        let ordering = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(a_part), Some(b_part)) => match (a_part.parse::<u64>(), b_part.parse::<u64>()) {
+                (Ok(a_number), Ok(b_number)) => a_number.cmp(&b_number),
+                _ => a_part.cmp(b_part),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Fail if the channel's season was closed, so its final leaderboards are not changed
+pub fn ensure_open(channel: &Channel) -> Result<(), String> {
+    let Some(season) = &channel.season else {
+        return Ok(());
+    };
+    let record = channel.leaderboard_dir().join(CLOSED_RECORD);
+    if record.exists() {
+        return Err(format!(
+            "Season {} of channel {} is closed. Pass --season or update the season file to score another season",
+            season, channel.id
+        ));
+    }
+    Ok(())
+}
+
+/// Update the channel's all-time leaderboards, mark its season as closed and create the
+/// directory of the next season, which starts with empty leaderboards
+pub fn close_season(
+    channel: &Channel,
+    next: &str,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let season = channel
+        .season
+        .as_ref()
+        .ok_or("No season to close: pass --season or write the current season to --season-file")?;
+    if season == next {
+        return Err(format!("The next season must differ from the current season {}", season).into());
+    }
+    ensure_open(channel)?;
+
+    // The all-time leaderboards and the record are committed together, so rolling back
+    // reopens the season
+    let transaction = Transaction::begin(&channel.output_dir)?;
+    save_all_time(channel, config, &transaction)?;
+
+    let record = ClosedSeason {
+        season: season.clone(),
+        next: next.to_string(),
+        closed_at: Utc::now(),
+    };
+    transaction.write(
+        &channel.leaderboard_dir().join(CLOSED_RECORD),
+        serde_json::to_string_pretty(&record)?.as_bytes(),
+    )?;
+    transaction.commit()?;
+    fs::create_dir_all(season_dir(&channel.output_dir, next))?;
+    info!(
+        "Closed season {} of channel {}, season {} has started",
+        season, channel.id, next
+    );
+    Ok(())
+}
@@ -1,9 +1,13 @@
 use std::collections::HashMap;
 
 use dotenv::dotenv;
-use log::debug;
+use log::{debug, info};
+use twitch_api::eventsub::stream::StreamOfflineV1;
+use twitch_api::eventsub::Transport;
 use twitch_api::helix::chat::{GetChannelChatBadgesRequest, GetGlobalChatBadgesRequest};
+use twitch_api::helix::users::GetUsersRequest;
 use twitch_api::helix::videos::GetVideosRequest;
+use twitch_api::types::UserId;
 use twitch_api::twitch_oauth2::{AppAccessToken, ClientId, ClientSecret};
 use twitch_api::HelixClient;
 
@@ -17,29 +21,28 @@ pub struct TwitchAPIWrapper {
 }
 
 impl TwitchAPIWrapper {
-    pub async fn new() -> Result<Self, reqwest::Error> {
+    /// Fails if the app credentials are missing or Twitch does not grant an app access token
+    pub async fn new() -> Result<Self, Box<dyn std::error::Error>> {
         dotenv().ok();
         let client_id: ClientId = std::env::var("TWITCH_APPID")
             .map(ClientId::new)
-            .expect("TWITCH_APPID must be set");
+            .map_err(|_| "TWITCH_APPID must be set")?;
 
         let client_secret: ClientSecret = std::env::var("TWITCH_APPSECRET")
             .map(ClientSecret::new)
-            .expect("TWITCH_APPSECRET must be set");
+            .map_err(|_| "TWITCH_APPSECRET must be set")?;
 
         debug!("Creating HTTP Client for TwitchAPIWrapper");
         let http_client = reqwest::ClientBuilder::new()
             .user_agent(USER_AGENT)
-            .build()
-            .expect("Failed to create HTTP Client");
+            .build()?;
 
         let twitch: HelixClient<'static, reqwest::Client> =
             twitch_api::HelixClient::with_client(http_client.clone());
 
         let token =
             AppAccessToken::get_app_access_token(&http_client, client_id, client_secret, vec![])
-                .await
-                .unwrap();
+                .await?;
 
         Ok(Self { twitch, token })
     }
@@ -50,6 +53,60 @@ impl TwitchAPIWrapper {
         response.await.unwrap().data[0].id.clone().to_string()
     }
 
+    /// Ask Twitch to send stream.offline notifications for a channel to a webhook callback
+    pub async fn subscribe_stream_offline(
+        &self,
+        ch_id: String,
+        callback_url: String,
+        secret: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let subscription = self
+            .twitch
+            .create_eventsub_subscription(
+                StreamOfflineV1::broadcaster_user_id(ch_id),
+                Transport::webhook(callback_url, secret),
+                &self.token,
+            )
+            .await?;
+        info!(
+            "Created stream.offline subscription {} ({:?})",
+            subscription.id, subscription.status
+        );
+        Ok(())
+    }
+
+    /// The login name of a channel, e.g. to join its chat
+    pub async fn get_login(&self, ch_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let request = GetUsersRequest::ids(vec![UserId::new(ch_id.to_string())]);
+        let users = self.twitch.req_get(request, &self.token).await?.data;
+        match users.into_iter().next() {
+            Some(user) => Ok(user.login.to_string()),
+            None => Err(format!("Twitch has no channel with ID {}", ch_id).into()),
+        }
+    }
+
+    /// The profile image URL of each of the users, by user ID. Users that no longer exist are
+    /// left out
+    pub async fn get_profile_images(
+        &self,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+        let mut profile_images = HashMap::new();
+        // Helix returns at most 100 users per request
+        for chunk in user_ids.chunks(100) {
+            let ids: Vec<UserId> = chunk.iter().map(|id| UserId::new(id.clone())).collect();
+            let request = GetUsersRequest::ids(ids);
+            let users = self.twitch.req_get(request, &self.token).await?.data;
+            for user in users {
+                if let Some(profile_image_url) = user.profile_image_url {
+                    profile_images.insert(user.id.to_string(), profile_image_url);
+                }
+            }
+        }
+        debug!("Got the profile images of {} users", profile_images.len());
+        Ok(profile_images)
+    }
+
     pub async fn get_badges(
         &self,
         ch_id: String,
@@ -1,8 +1,30 @@
 #!/bin/bash
 # Simple bash script to stage results from chatdownloader to the
-# webpage
+# webpage. The current season's leaderboards are staged at the top,
+# with their ELO history in history/, the metrics' reports on its
+# streams in reports/, and the all-time leaderboards in all-time/
 
 CHATDOWNLOADER="chatdownloader"
 WEB="web/static"
+SEASON=$(cat season.txt)
 
-cp ${CHATDOWNLOADER}/*.json ${WEB}/
+# Copy the leaderboards in $1 to $2, leaving out the configs they were
+# scored with and the record of a closed season
+copy_leaderboards() {
+    for file in "$1"/*.json; do
+        case "$(basename "${file}")" in
+            *.config.json|closed.json) ;;
+            *) cp "${file}" "$2"/ ;;
+        esac
+    done
+}
+
+copy_leaderboards ${CHATDOWNLOADER}/seasons/${SEASON} ${WEB}
+mkdir -p ${WEB}/history
+cp ${CHATDOWNLOADER}/seasons/${SEASON}/history/*.jsonl ${WEB}/history/
+if [ -d ${CHATDOWNLOADER}/seasons/${SEASON}/reports ]; then
+    mkdir -p ${WEB}/reports
+    cp ${CHATDOWNLOADER}/seasons/${SEASON}/reports/*.json ${WEB}/reports/
+fi
+mkdir -p ${WEB}/all-time
+copy_leaderboards ${CHATDOWNLOADER}/all-time ${WEB}/all-time